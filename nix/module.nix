@@ -101,6 +101,18 @@ in {
         default = null;
         example = "20min";
       };
+
+      watchdog-sec = mkOption {
+        description = ''
+          Value of `WatchdogSec=` for the service. If the server stops
+          responding for this long, systemd considers it hung and kills it.
+
+          The watchdog is disabled if this option is set to `null`.
+        '';
+        type = with types; nullOr str;
+        default = "30s";
+        example = "2min";
+      };
     };
   };
 
@@ -152,10 +164,14 @@ in {
           config-file = pkgs.writers.writeJSON "config.json" config;
         in
         {
-          Type = "simple";
+          Type = "notify";
+          # Only the listener gets SIGTERM, so it can wait for running commands before exiting.
+          KillMode = "mixed";
           User = cfg.user;
           Group = cfg.group;
           ExecStart = "${cfg.package}/bin/webhook-listener ${config-file}";
+        } // lib.optionalAttrs (cfg.watchdog-sec != null) {
+          WatchdogSec = cfg.watchdog-sec;
         };
     };
   };
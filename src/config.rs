@@ -1,32 +1,74 @@
+use std::collections::HashMap;
+use std::env;
 use std::path::{Path, PathBuf};
 use std::fs::{self, File};
-use std::io;
+use std::io::{self, Read};
+use std::sync::{Arc, RwLock};
 use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::str::FromStr;
 use serde::Deserialize;
-use std::time::Duration;
+use crate::provider::Provider;
+use crate::secret::Redacted;
+use std::time::{Duration, SystemTime};
 
 /// All the application configuration is stored in this structure.
 #[derive(Deserialize, PartialEq, Clone, Debug)]
 pub struct Config {
-    /// Path to the file containing the GitHub secret.
-    pub secret_path: PathBuf,
+    /// The default profile, whose fields are given at the top level of the configuration. It is
+    /// used for the socket bound via [`listen`](Config::listen) and for a single socket passed from
+    /// systemd when [`sockets`](Config::sockets) is empty.
+    #[serde(flatten)]
+    pub profile: Profile,
 
-    /// The secret string shared with GitHub that is used to verify signed requests.
-    #[serde(skip_deserializing)]
-    pub secret: String,
-
-    /// Event-command pairs. Each element of this array should be matched (and optionally executed)
-    /// against the commands in gaide.
-    pub commands: Vec<Command>,
+    /// Profiles for named sockets passed from systemd, keyed by their name (i.e. the
+    /// `FileDescriptorName=` of the socket unit).
+    ///
+    /// When this is non-empty, every socket passed from systemd must have a matching entry.
+    #[serde(default)]
+    pub sockets: HashMap<String, Profile>,
 
-    /// The maximum time the server should spend sitting idle waiting for a connection before
-    /// shutting itself down.
+    /// The maximum time the server should spend sitting idle before shutting itself down. The
+    /// server is only considered idle while there are no open connections and no running commands.
     ///
     /// This is pretty relevant as webhook event are relatively rare. Shutting down and waiting for
     /// socket (re)activation spares a few ressources.
     #[serde(default)]
     #[serde(with = "humantime_serde")]
     pub max_idle_time: Option<Duration>,
+
+    /// An address the server should bind itself, instead of relying on systemd for socket
+    /// activation. See [`ListenAddr`] for the accepted formats.
+    ///
+    /// This can be overridden with the `--listen` command line option.
+    #[serde(default)]
+    pub listen: Option<ListenAddr>,
+
+    /// How long to wait for open connections and running commands before exiting, either after
+    /// [`max_idle_time`](Config::max_idle_time) has passed or when receiving `SIGTERM`.
+    ///
+    /// This should be shorter than systemd's `TimeoutStopSec=`, which is 90 seconds by default.
+    #[serde(default = "default_shutdown_grace_period")]
+    #[serde(with = "humantime_serde")]
+    pub shutdown_grace_period: Duration,
+
+    /// Settings for terminating TLS in the server itself. Connections are served as plain HTTP if
+    /// this is not set.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    /// Settings for the HTTP connections.
+    #[serde(default)]
+    pub http: HttpConfig,
+
+    /// Settings for rejecting deliveries which have been received before. Repeated deliveries are
+    /// accepted if this is not set.
+    #[serde(default)]
+    pub replay_protection: Option<ReplayConfig>,
+}
+
+fn default_shutdown_grace_period() -> Duration {
+    Duration::from_secs(60)
 }
 
 impl Config {
@@ -34,17 +76,347 @@ impl Config {
         let file = File::open(path.as_ref()).map_err(ConfigError::IoReadingConfig)?;
         let mut config: Config = serde_json::from_reader(file)?;
 
-        if config.secret_path.is_relative() {
-            eprintln!("warning: `secret_path` in configuration is a relative path.\
-                       This will be resolved relative to the server's CWD at runtime,\
-                       which is most likely not what you want!");
+        // With named sockets, the top level doesn't have to configure a profile at all. It is only
+        // needed for `listen`, which is checked once the command line has been taken into account.
+        let top_level_used = config.sockets.is_empty() || !config.profile.is_empty();
+        if top_level_used {
+            config.profile.read_secrets()?;
+        }
+        for profile in config.sockets.values_mut() {
+            profile.read_secrets()?;
+        }
+
+        // Replay protection would silently do nothing for requests without a delivery ID.
+        if config.replay_protection.is_some() {
+            if top_level_used && config.profile.provider.delivery_header().is_none() {
+                return Err(ConfigError::NoDeliveryHeader("the top level".to_string()));
+            }
+            for (name, profile) in &config.sockets {
+                if profile.provider.delivery_header().is_none() {
+                    return Err(ConfigError::NoDeliveryHeader(format!("socket {:?}", name)));
+                }
+            }
+        }
+
+        // Commands inherit our environment, but have no business seeing the secrets.
+        for (var, _) in config.secret_variables() {
+            env::remove_var(var);
         }
-        config.secret = fs::read_to_string(&config.secret_path)
-            .map(|mut s| { s.truncate(s.trim_end().len()); s })
-            .map_err(ConfigError::IoReadingSecret)?;
 
         Ok(config)
     }
+
+    /// Returns the environment variables which secrets have been read from, along with their
+    /// values.
+    pub fn secret_variables(&self) -> Vec<(String, Redacted)> {
+        let profiles = std::iter::once(&self.profile).chain(self.sockets.values());
+        let secrets = profiles.flat_map(|profile| profile.secrets.iter().chain(profile.repository_secrets.values()));
+        secrets
+            .filter_map(|secret| match &secret.source {
+                SecretSource::Env(var) => Some((var.clone(), secret.value())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A profile holds the settings which may differ between the sockets the server is listening on.
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct Profile {
+    /// Path to the file containing the GitHub secret. This is a shorthand for an entry in
+    /// [`secrets`](Profile::secrets) which never expires.
+    #[serde(default)]
+    pub secret_path: Option<PathBuf>,
+
+    /// Where to get the GitHub secret from, e.g. `{"credential": "github-secret"}`. Like
+    /// [`secret_path`](Profile::secret_path), this is a shorthand for an entry in
+    /// [`secrets`](Profile::secrets) which never expires.
+    #[serde(default)]
+    pub secret: Option<SecretSource>,
+
+    /// The secrets shared with GitHub that are used to verify signed requests. Requests signed with
+    /// any of the active secrets are accepted, so a new secret can be added here before it is
+    /// changed on GitHub, and the old one removed (or left to expire) afterwards.
+    #[serde(default)]
+    pub secrets: Vec<Secret>,
+
+    /// Secrets for individual repositories, keyed by the full name of the repository (e.g.
+    /// `"octo-org/hello-world"`) or by the name of its owner or organization (e.g. `"octo-org"`).
+    /// The key is taken from the payload, and requests about a repository listed here must be
+    /// signed with its secret. [`secrets`](Profile::secrets) only apply to all other requests.
+    /// Organizations are only matched for events which aren't about a repository.
+    #[serde(default)]
+    pub repository_secrets: HashMap<String, Secret>,
+
+    /// The service sending webhooks, which determines how requests are verified and how events
+    /// are named. Defaults to GitHub.
+    #[serde(default)]
+    pub provider: Provider,
+
+    /// Whether to accept requests signed with HMAC-SHA1 in the `X-Hub-Signature` header, which is
+    /// all older GitHub Enterprise Server instances send. It is only checked if there's no SHA-256
+    /// signature, and every use is logged, since SHA-1 shouldn't be relied on anymore.
+    #[serde(default)]
+    pub allow_sha1: bool,
+
+    /// How far the timestamp of a request may be from the current time, for providers which sign
+    /// a timestamp along with the payload. Requests outside of this window are rejected, so a
+    /// captured request can't be replayed later on.
+    #[serde(default = "default_timestamp_tolerance")]
+    #[serde(with = "humantime_serde")]
+    pub timestamp_tolerance: Duration,
+
+    /// How old an event may be, judging by the timestamps in its payload (e.g. the time of the
+    /// head commit of a push). Older events are rejected, so a delivery that was held up or
+    /// replayed doesn't run commands long after the fact.
+    ///
+    /// The timestamp of a commit is when it was made, not when it was pushed, so pushing an older
+    /// commit gets rejected as well.
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub max_event_age: Option<Duration>,
+
+    /// Whether to reject events whose age can't be determined, because their payload has none of
+    /// the timestamps [`max_event_age`](Profile::max_event_age) looks at. Otherwise they are
+    /// accepted with a warning.
+    #[serde(default)]
+    pub reject_undated_events: bool,
+
+    /// Event-command pairs. Each element of this array should be matched (and optionally executed)
+    /// against the commands in gaide.
+    #[serde(default)]
+    pub commands: Vec<Command>,
+}
+
+fn default_timestamp_tolerance() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// How long before a secret expires we start warning about it.
+pub const SECRET_EXPIRY_WARNING: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+impl Profile {
+    /// Checks whether neither secrets nor commands are configured, i.e. whether this profile would
+    /// never do anything.
+    pub fn is_empty(&self) -> bool {
+        self.secret_path.is_none()
+            && self.secret.is_none()
+            && self.secrets.is_empty()
+            && self.repository_secrets.is_empty()
+            && self.commands.is_empty()
+    }
+
+    /// Adds the secrets from [`secret_path`](Profile::secret_path) and
+    /// [`secret`](Profile::secret) to [`secrets`](Profile::secrets), and loads all of them.
+    fn read_secrets(&mut self) -> Result<(), ConfigError> {
+        let shorthands = self.secret_path.iter().cloned().map(SecretSource::File).chain(self.secret.clone());
+        for (i, source) in shorthands.enumerate() {
+            self.secrets.insert(i, Secret {
+                source,
+                not_after: None,
+                value: SecretValue::default(),
+            });
+        }
+        if self.secrets.is_empty() && self.repository_secrets.is_empty() && self.provider.uses_secrets() {
+            return Err(ConfigError::MissingSecret);
+        }
+
+        let now = SystemTime::now();
+        for secret in self.secrets.iter_mut().chain(self.repository_secrets.values_mut()) {
+            secret.load()?;
+            if !secret.is_active(now) {
+                eprintln!("warning: secret from {} has expired and will not be accepted.", secret.source);
+            } else if let Some(expires_in) = secret.expires_in(now).filter(|d| *d < SECRET_EXPIRY_WARNING) {
+                eprintln!("warning: secret from {} expires in {}.", secret.source, format_secs(expires_in));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A secret shared with GitHub, see [`Profile::secrets`].
+///
+/// In the configuration, the [`source`](Secret::source) is given by one of the keys `file` (or
+/// `path`), `credential` and `env`, next to `not_after`.
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(try_from = "RawSecret")]
+pub struct Secret {
+    /// Where to get the secret from.
+    pub source: SecretSource,
+
+    /// The point in time after which requests signed with this secret are rejected, e.g.
+    /// `"2025-01-31T00:00:00Z"`. The secret doesn't expire if unset.
+    pub not_after: Option<SystemTime>,
+
+    /// The value loaded from [`source`](Secret::source).
+    value: SecretValue,
+}
+
+/// A [`Secret`] as written in the configuration.
+#[derive(Deserialize)]
+struct RawSecret {
+    #[serde(alias = "path")]
+    file: Option<PathBuf>,
+    credential: Option<String>,
+    env: Option<String>,
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    not_after: Option<SystemTime>,
+}
+
+impl TryFrom<RawSecret> for Secret {
+    type Error = String;
+
+    fn try_from(raw: RawSecret) -> Result<Self, Self::Error> {
+        let source = match (raw.file, raw.credential, raw.env) {
+            (Some(path), None, None) => SecretSource::File(path),
+            (None, Some(name), None) => SecretSource::Credential(name),
+            (None, None, Some(var)) => SecretSource::Env(var),
+            _ => return Err("expected exactly one of `file`, `credential` and `env` for secret".to_string()),
+        };
+        Ok(Secret {
+            source,
+            not_after: raw.not_after,
+            value: SecretValue::default(),
+        })
+    }
+}
+
+/// Where the value of a [`Secret`] comes from. In the configuration, this is an object with a
+/// single key, e.g. `{"file": "/run/secrets/github.txt"}`.
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretSource {
+    /// A file, which is read again whenever it changes. `path` may be used instead of `file`.
+    #[serde(alias = "path")]
+    File(PathBuf),
+
+    /// A systemd credential (see `LoadCredential=` in `systemd.exec(5)`), i.e. the file with this
+    /// name in `$CREDENTIALS_DIRECTORY`.
+    Credential(String),
+
+    /// An environment variable.
+    Env(String),
+}
+
+impl Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            SecretSource::File(path) => write!(f, "file {}", path.display()),
+            SecretSource::Credential(name) => write!(f, "credential {:?}", name),
+            SecretSource::Env(var) => write!(f, "environment variable {}", var),
+        }
+    }
+}
+
+/// The current value of a [`Secret`]. Clones share the value, so all of them see a reloaded file.
+#[derive(Clone, Default)]
+struct SecretValue(Arc<RwLock<LoadedSecret>>);
+
+#[derive(Default)]
+struct LoadedSecret {
+    value: Redacted,
+
+    /// The modification time of the file the secret was read from, if any.
+    modified: Option<SystemTime>,
+}
+
+impl PartialEq for SecretValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.read().unwrap().value == other.0.read().unwrap().value
+    }
+}
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        fmt::Debug::fmt(&self.0.read().unwrap().value, f)
+    }
+}
+
+impl Secret {
+    /// Creates a secret with the given value, as if it had been loaded from `source`.
+    #[cfg(test)]
+    pub fn with_value(source: SecretSource, not_after: Option<SystemTime>, value: &str) -> Secret {
+        let loaded = LoadedSecret { value: value.into(), modified: None };
+        Secret { source, not_after, value: SecretValue(Arc::new(RwLock::new(loaded))) }
+    }
+
+    /// Loads the value of the secret from its [`source`](Secret::source).
+    fn load(&mut self) -> Result<(), ConfigError> {
+        let loaded = match &self.source {
+            SecretSource::File(path) => {
+                if path.is_relative() {
+                    eprintln!("warning: secret file {} in configuration is a relative path.\
+                               This will be resolved relative to the server's CWD at runtime,\
+                               which is most likely not what you want!", path.display());
+                }
+                read_secret_file(path)
+            },
+            SecretSource::Credential(name) => {
+                let dir = env::var_os("CREDENTIALS_DIRECTORY")
+                    .ok_or_else(|| ConfigError::NoCredentialsDirectory(name.clone()))?;
+                read_secret_file(&Path::new(&dir).join(name))
+            },
+            SecretSource::Env(var) => {
+                let value = env::var(var).map_err(|_| ConfigError::MissingSecretVariable(var.clone()))?;
+                Ok(LoadedSecret { value: trim_secret(value), modified: None })
+            },
+        };
+        *self.value.0.write().unwrap() = loaded.map_err(ConfigError::IoReadingSecret)?;
+        Ok(())
+    }
+
+    /// Returns the current value of the secret. Secrets from a file are read again first if the
+    /// file has changed since it was last read.
+    pub fn value(&self) -> Redacted {
+        if let SecretSource::File(path) = &self.source {
+            self.reload_if_changed(path);
+        }
+        self.value.0.read().unwrap().value.clone()
+    }
+
+    fn reload_if_changed(&self, path: &Path) {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        if modified.is_none() || modified == self.value.0.read().unwrap().modified {
+            return;
+        }
+        match read_secret_file(path) {
+            Ok(loaded) => {
+                println!("Reloaded secret from {}", self.source);
+                *self.value.0.write().unwrap() = loaded;
+            },
+            Err(e) => eprintln!("Failed to reload secret from {}, keeping the old value: {}", self.source, e),
+        }
+    }
+
+    /// Whether requests signed with this secret are accepted at `now`.
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.not_after.is_none_or(|not_after| now < not_after)
+    }
+
+    /// Returns how long the secret is still active for, if it expires at all.
+    pub fn expires_in(&self, now: SystemTime) -> Option<Duration> {
+        self.not_after?.duration_since(now).ok()
+    }
+}
+
+fn read_secret_file(path: &Path) -> io::Result<LoadedSecret> {
+    let mut file = File::open(path)?;
+    let modified = file.metadata()?.modified().ok();
+    let mut value = String::new();
+    file.read_to_string(&mut value)?;
+    Ok(LoadedSecret { value: trim_secret(value), modified })
+}
+
+/// Removes trailing whitespace, in particular the newline most editors add at the end of a file.
+fn trim_secret(mut value: String) -> Redacted {
+    value.truncate(value.trim_end().len());
+    Redacted::new(value)
+}
+
+/// Formats `duration` for humans, leaving out anything below seconds.
+pub fn format_secs(duration: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(duration.as_secs()))
 }
 
 impl From<serde_json::Error> for ConfigError {
@@ -57,12 +429,123 @@ impl From<serde_json::Error> for ConfigError {
     }
 }
 
+/// Settings for serving HTTPS. Both files are watched for changes, so renewed certificates are
+/// picked up without a restart.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// Path to the PEM-encoded certificate chain, starting with the server's own certificate.
+    pub cert_path: PathBuf,
+
+    /// Path to the PEM-encoded private key belonging to the certificate.
+    pub key_path: PathBuf,
+
+    /// Path to a PEM-encoded bundle of CA certificates. When set, clients must present a
+    /// certificate signed by one of these CAs (mutual TLS).
+    ///
+    /// Requests from clients authenticated this way are not required to carry a signature.
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+
+    /// If non-empty, client certificates must additionally carry one of these names, either as
+    /// the subject's common name or as a subject alternative name.
+    #[serde(default)]
+    pub allowed_clients: Vec<String>,
+}
+
+/// Settings for remembering the IDs of deliveries (e.g. `X-GitHub-Delivery`), so that a captured
+/// request can't be replayed to run the commands again.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplayConfig {
+    /// How long delivery IDs are remembered. GitHub lets users redeliver events for three days, so
+    /// that's the default.
+    #[serde(default = "default_delivery_ttl")]
+    #[serde(with = "humantime_serde")]
+    pub ttl: Duration,
+
+    /// How many delivery IDs are remembered at most. Since the IDs aren't signed, a captured
+    /// request can be replayed with any number of made up ones, so once there are this many, the
+    /// oldest are forgotten before their TTL is up.
+    #[serde(default = "default_max_deliveries")]
+    pub max_deliveries: usize,
+
+    /// A file to keep the delivery IDs in, so they are remembered when the server is restarted
+    /// (e.g. after exiting because of [`max_idle_time`](Config::max_idle_time)). They are only
+    /// kept in memory if this is not set.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+
+    /// Whether deliveries which have been received before are accepted anyway, e.g. so an event
+    /// can be redelivered from GitHub's UI to run the commands again. They are logged either way.
+    ///
+    /// This turns off the rejection of replayed requests completely, since they can't be told
+    /// apart from redeliveries.
+    #[serde(default)]
+    pub allow_redeliveries: bool,
+}
+
+fn default_delivery_ttl() -> Duration {
+    Duration::from_secs(3 * 24 * 60 * 60)
+}
+
+fn default_max_deliveries() -> usize {
+    100_000
+}
+
+/// Settings for the HTTP connections. Both HTTP/1 and HTTP/2 (over TLS or as cleartext `h2c`) are
+/// served.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct HttpConfig {
+    /// Whether HTTP/1 connections are kept open for further requests after a response.
+    ///
+    /// This is disabled by default, since keeping connections alive breaks serving them on OSX.
+    #[serde(default)]
+    pub keep_alive: bool,
+
+    /// The maximum time to wait for a client to send the headers of a request, once it has started
+    /// sending them. Defaults to 30 seconds.
+    #[serde(default = "default_header_read_timeout")]
+    #[serde(with = "humantime_serde")]
+    pub header_read_timeout: Duration,
+
+    /// The interval at which HTTP/2 keep-alive pings are sent. Connections which don't answer in
+    /// time are closed. No pings are sent if unset.
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub keep_alive_interval: Option<Duration>,
+
+    /// How long a connection may sit idle between requests before it is closed. Open connections
+    /// keep the server from becoming idle, so this matters when keep-alive or HTTP/2 is used.
+    #[serde(default = "default_idle_timeout")]
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            keep_alive: false,
+            header_read_timeout: default_header_read_timeout(),
+            keep_alive_interval: None,
+            idle_timeout: default_idle_timeout(),
+        }
+    }
+}
+
+fn default_header_read_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
 /// Represents an event-command pair. The command is run whenever the given event is received from
 /// GitHub's API.
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct Command {
     /// The name of an event from the GitHub API. A full list of events can be found in [GitHub's
-    /// documenation][gh-events].
+    /// documenation][gh-events]. For GitLab, the event names are converted to the same style, e.g.
+    /// `Push Hook` becomes `push` and `Merge Request Hook` becomes `merge_request`.
     ///
     /// [gh-events]: https://docs.github.com/en/webhooks/webhook-events-and-payloads
     pub event: String,
@@ -73,6 +556,59 @@ pub struct Command {
     /// Additional arguments to bass to [`command`](command).
     #[serde(default)]
     pub args: Vec<String>,
+
+    /// If non-empty, the command only runs for requests from clients which authenticated with a
+    /// certificate carrying one of these names. See [`TlsConfig::client_ca_path`].
+    #[serde(default)]
+    pub identities: Vec<String>,
+}
+
+/// An address which the server can bind itself when not using socket activation.
+///
+/// In the configuration file and on the command line, this is written as either
+/// `tcp:<ip>:<port>` (e.g. `tcp:0.0.0.0:8080` or `tcp:[::1]:8080`) or `unix:<path>` (e.g.
+/// `unix:/run/webhook-listener.sock`).
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            addr.parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|e| format!("invalid TCP address {:?}: {}", addr, e))
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("missing path for UNIX socket".to_string());
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else {
+            Err(format!("invalid listen address {:?}: expected `tcp:<ip>:<port>` or `unix:<path>`", s))
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
 /// Errors that can occur when reading configuration.
@@ -80,8 +616,17 @@ pub struct Command {
 pub enum ConfigError {
     /// An IO error occured while reading the configuration, such as failing to read the file.
     IoReadingConfig(io::Error),
-    /// An IO error occured while reading the secret file linked via `secret_path`.
+    /// An IO error occured while reading a secret file or credential.
     IoReadingSecret(io::Error),
+    /// None of `secret_path`, `secret`, `secrets` and `repository_secrets` is set for a profile.
+    MissingSecret,
+    /// A secret is to be read from a systemd credential, but `$CREDENTIALS_DIRECTORY` is unset.
+    NoCredentialsDirectory(String),
+    /// A secret is to be read from an environment variable which is unset or not valid UTF-8.
+    MissingSecretVariable(String),
+    /// `replay_protection` is set, but the provider of a profile (named here) has no header with
+    /// a delivery ID, e.g. an HMAC scheme without `delivery_header`.
+    NoDeliveryHeader(String),
     /// Decoding the file failed, e.g. if JSON is missing comma.
     SerdeError(serde_json::Error),
 }
@@ -91,6 +636,13 @@ impl Display for ConfigError {
         match self {
             ConfigError::IoReadingConfig(e) => write!(f, "io error while reading configuration file: {}", e),
             ConfigError::IoReadingSecret(e) => write!(f, "io error while reading secret file: {}", e),
+            ConfigError::MissingSecret => write!(f, "no secret configured, set `secret_path`, `secret`, `secrets` or `repository_secrets`"),
+            ConfigError::NoCredentialsDirectory(name) =>
+                write!(f, "cannot read credential {:?}: $CREDENTIALS_DIRECTORY is not set (see `LoadCredential=`)", name),
+            ConfigError::MissingSecretVariable(var) =>
+                write!(f, "environment variable {} for secret is unset or not valid UTF-8", var),
+            ConfigError::NoDeliveryHeader(profile) =>
+                write!(f, "`replay_protection` needs a delivery ID, but the provider at {} has no `delivery_header`", profile),
             ConfigError::SerdeError(e) => write!(f, "decoding error: {}", e),
         }
     }
@@ -98,21 +650,12 @@ impl Display for ConfigError {
 
 #[cfg(test)]
 mod tests {
-    use super::{Config, Command, ConfigError};
+    use super::{Config, Command, ConfigError, HttpConfig, ListenAddr, Profile, Provider, ReplayConfig, Secret, SecretSource, TlsConfig};
+    use crate::systemd_socket::tests::lock_env;
+    use crate::test_util::assert_matches;
+    use std::collections::HashMap;
     use std::path::{Path, PathBuf};
-    use std::time::Duration;
-
-    macro_rules! assert_matches {
-        ( $e:expr , $pat:pat ) => {
-            assert_matches!($e, $pat => ())
-        };
-        ( $e:expr , $pat:pat => $c:expr ) => {
-            match $e {
-                $pat => $c,
-                ref e => panic!("assertion failed: `{:?}` does not match `{}`", e, stringify!($pat))
-            }
-        };
-    }
+    use std::time::{Duration, SystemTime};
 
     macro_rules! assert_contains {
         ( $a:expr , $b:expr ) => {
@@ -144,16 +687,32 @@ mod tests {
         "#;
         let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
         let expected_config = Config {
-            secret_path: Path::new("/path/to/secret.txt").to_path_buf(),
-            secret: "".to_string(), // We didn't ask it to read file
+            profile: Profile {
+                secret_path: Some(Path::new("/path/to/secret.txt").to_path_buf()),
+                secret: None,
+                secrets: vec![], // We didn't ask it to read the file
+                repository_secrets: HashMap::new(),
+                provider: Provider::Github,
+                allow_sha1: false,
+                timestamp_tolerance: Duration::from_secs(5 * 60),
+                max_event_age: None,
+                reject_undated_events: false,
+                commands: vec![
+                    Command {
+                        event: "ping".to_string(),
+                        command: "/usr/bin/handle-ping".to_string(),
+                        args: vec![],
+                        identities: vec![],
+                    },
+                ],
+            },
+            sockets: HashMap::new(),
             max_idle_time: Some(Duration::from_secs(600)),
-            commands: vec![
-                Command {
-                    event: "ping".to_string(),
-                    command: "/usr/bin/handle-ping".to_string(),
-                    args: vec![],
-                },
-            ],
+            listen: None,
+            shutdown_grace_period: Duration::from_secs(60),
+            tls: None,
+            http: HttpConfig::default(),
+            replay_protection: None,
         };
         assert_eq!(parsed_config, expected_config);
     }
@@ -172,6 +731,7 @@ mod tests {
             event: "ping".to_string(),
             command: "/usr/bin/handle-ping".to_string(),
             args: vec![],
+            identities: vec![],
         };
         assert_eq!(expected_command, parsed_command);
     }
@@ -198,25 +758,44 @@ mod tests {
         let parse_result = Config::from_path("examples/config.json");
         let parsed_config = assert_matches!(parse_result, Ok(c @ Config { .. }) => c);
         let expected_config = Config {
-            secret_path: PathBuf::from("./examples/secret.txt"),
-            secret: "mysecret".to_string(),
+            profile: Profile {
+                secret_path: Some(PathBuf::from("./examples/secret.txt")),
+                secret: None,
+                secrets: vec![
+                    Secret::with_value(SecretSource::File(PathBuf::from("./examples/secret.txt")), None, "mysecret"),
+                ],
+                repository_secrets: HashMap::new(),
+                provider: Provider::Github,
+                allow_sha1: false,
+                timestamp_tolerance: Duration::from_secs(5 * 60),
+                max_event_age: None,
+                reject_undated_events: false,
+                commands: vec![
+                    Command {
+                        event: "ping".to_string(),
+                        command: "/bin/echo".to_string(),
+                        args: vec![
+                            "Got ping event!!".to_string()
+                        ],
+                        identities: vec![],
+                    },
+                    Command {
+                        event: "push".to_string(),
+                        command: "/bin/echo".to_string(),
+                        args: vec![
+                            "Got push event!!".to_string()
+                        ],
+                        identities: vec![],
+                    },
+                ],
+            },
+            sockets: HashMap::new(),
             max_idle_time: Some(Duration::from_secs(60 * 60)),
-            commands: vec![
-                Command {
-                    event: "ping".to_string(),
-                    command: "/bin/echo".to_string(),
-                    args: vec![
-                        "Got ping event!!".to_string()
-                    ],
-                },
-                Command {
-                    event: "push".to_string(),
-                    command: "/bin/echo".to_string(),
-                    args: vec![
-                        "Got push event!!".to_string()
-                    ],
-                },
-            ],
+            listen: None,
+            shutdown_grace_period: Duration::from_secs(60),
+            tls: None,
+            http: HttpConfig::default(),
+            replay_protection: None,
         };
         assert_eq!(parsed_config, expected_config);
     }
@@ -234,11 +813,484 @@ mod tests {
         "#;
         let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
         let expected_config = Config {
-            secret_path: Path::new("/path/to/secret.txt").to_path_buf(),
-            secret: "".to_string(), // We didn't ask it to read file
+            profile: Profile {
+                secret_path: Some(Path::new("/path/to/secret.txt").to_path_buf()),
+                secret: None,
+                secrets: vec![], // We didn't ask it to read the file
+                repository_secrets: HashMap::new(),
+                provider: Provider::Github,
+                allow_sha1: false,
+                timestamp_tolerance: Duration::from_secs(5 * 60),
+                max_event_age: None,
+                reject_undated_events: false,
+                commands: vec![],
+            },
+            sockets: HashMap::new(),
             max_idle_time: None,
-            commands: vec![],
+            listen: None,
+            shutdown_grace_period: Duration::from_secs(60),
+            tls: None,
+            http: HttpConfig::default(),
+            replay_protection: None,
         };
         assert_eq!(parsed_config, expected_config);
     }
+
+    #[test]
+    fn deserialize_socket_profiles() {
+        let config_json = r#"
+            {
+                "secret_path": "/path/to/secret.txt",
+                "commands": [ ],
+
+                "sockets": {
+                    "project-a": {
+                        "secret_path": "/path/to/secret-a.txt",
+                        "commands": [
+                            {
+                                "event": "push",
+                                "command": "/usr/bin/deploy-a"
+                            }
+                        ]
+                    }
+                }
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        assert_eq!(parsed_config.profile.secret_path, Some(PathBuf::from("/path/to/secret.txt")));
+        assert_eq!(parsed_config.sockets.len(), 1);
+        let expected_profile = Profile {
+            secret_path: Some(PathBuf::from("/path/to/secret-a.txt")),
+            secret: None,
+            secrets: vec![], // We didn't ask it to read the file
+            repository_secrets: HashMap::new(),
+            provider: Provider::Github,
+            allow_sha1: false,
+            timestamp_tolerance: Duration::from_secs(5 * 60),
+            max_event_age: None,
+            reject_undated_events: false,
+            commands: vec![
+                Command {
+                    event: "push".to_string(),
+                    command: "/usr/bin/deploy-a".to_string(),
+                    args: vec![],
+                    identities: vec![],
+                },
+            ],
+        };
+        assert_eq!(parsed_config.sockets["project-a"], expected_profile);
+    }
+
+    #[test]
+    fn load_config_with_only_sockets() {
+        let dir = std::env::temp_dir().join(format!("webhook-listener-sockets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("secret-a.txt"), "secret-a\n").unwrap();
+        let config_json = format!(r#"
+            {{
+                "sockets": {{
+                    "project-a": {{
+                        "secret_path": "{}",
+                        "commands": [ {{ "event": "push", "command": "/usr/bin/deploy-a" }} ]
+                    }}
+                }}
+            }}
+        "#, dir.join("secret-a.txt").display());
+        std::fs::write(dir.join("config.json"), config_json).unwrap();
+
+        let config = Config::from_path(dir.join("config.json")).expect("valid config");
+        assert!(config.profile.is_empty());
+        assert_eq!(config.sockets["project-a"].secrets[0].value(), "secret-a".into());
+
+        // Without sockets, the top level is all there is and must have a secret.
+        std::fs::write(dir.join("config.json"), r#"{ "commands": [] }"#).unwrap();
+        assert_matches!(Config::from_path(dir.join("config.json")), Err(ConfigError::MissingSecret));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deserialize_secrets() {
+        let config_json = r#"
+            {
+                "secrets": [
+                    { "path": "/path/to/old-secret.txt", "not_after": "2024-06-01T00:00:00Z" },
+                    { "path": "/path/to/new-secret.txt" }
+                ],
+                "commands": [ ]
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        assert_eq!(parsed_config.profile.secret_path, None);
+        let not_after = SystemTime::UNIX_EPOCH + Duration::from_secs(1717200000);
+        let expected_secrets = vec![
+            Secret::with_value(SecretSource::File(PathBuf::from("/path/to/old-secret.txt")), Some(not_after), ""),
+            Secret::with_value(SecretSource::File(PathBuf::from("/path/to/new-secret.txt")), None, ""),
+        ];
+        assert_eq!(parsed_config.profile.secrets, expected_secrets);
+
+        let [old, new] = &expected_secrets[..] else { unreachable!() };
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(old.is_active(not_after - day));
+        assert_eq!(old.expires_in(not_after - day), Some(day));
+        assert!(!old.is_active(not_after));
+        assert_eq!(old.expires_in(not_after + day), None);
+        assert!(new.is_active(not_after + day));
+        assert_eq!(new.expires_in(not_after), None);
+    }
+
+    #[test]
+    fn deserialize_repository_secrets() {
+        let config_json = r#"
+            {
+                "repository_secrets": {
+                    "octo-org/hello-world": { "path": "/path/to/hello-world.txt" },
+                    "octo-org": { "path": "/path/to/octo-org.txt", "not_after": "2024-06-01T00:00:00Z" }
+                },
+                "commands": [ ]
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        let secrets = &parsed_config.profile.repository_secrets;
+        assert_eq!(secrets.len(), 2);
+        assert_eq!(secrets["octo-org/hello-world"].source, SecretSource::File(PathBuf::from("/path/to/hello-world.txt")));
+        assert_eq!(secrets["octo-org"].not_after, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1717200000)));
+        assert!(parsed_config.profile.secrets.is_empty());
+    }
+
+    #[test]
+    fn deserialize_secret_sources() {
+        let config_json = r#"
+            {
+                "secret": { "credential": "github-secret" },
+                "secrets": [
+                    { "env": "GITHUB_SECRET", "not_after": "2024-06-01T00:00:00Z" },
+                    { "file": "/path/to/secret.txt" }
+                ],
+                "commands": [ ]
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        let profile = parsed_config.profile;
+        assert_eq!(profile.secret, Some(SecretSource::Credential("github-secret".to_string())));
+        let sources = profile.secrets.iter().map(|secret| secret.source.clone()).collect::<Vec<_>>();
+        assert_eq!(sources, vec![
+            SecretSource::Env("GITHUB_SECRET".to_string()),
+            SecretSource::File(PathBuf::from("/path/to/secret.txt")),
+        ]);
+        assert!(profile.secrets[0].not_after.is_some());
+    }
+
+    #[test]
+    fn secret_needs_exactly_one_source() {
+        let config_json = r#"
+            {
+                "secrets": [ { "file": "/path/to/secret.txt", "env": "GITHUB_SECRET" } ],
+                "commands": [ ]
+            }
+        "#;
+        let err = serde_json::from_str::<Config>(config_json).unwrap_err();
+        assert_contains!(err, "expected exactly one of `file`, `credential` and `env`");
+    }
+
+    #[test]
+    fn load_secret_sources() {
+        // The environment is shared with other tests, including ones spawning processes.
+        let _l = lock_env();
+        let dir = std::env::temp_dir().join(format!("webhook-listener-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("github-secret"), "fromcredential\n").unwrap();
+
+        std::env::set_var("CREDENTIALS_DIRECTORY", &dir);
+        std::env::set_var("WEBHOOK_LISTENER_TEST_SECRET", "fromenv");
+        let mut profile = serde_json::from_str::<Profile>(r#"
+            {
+                "secret": { "credential": "github-secret" },
+                "secrets": [ { "env": "WEBHOOK_LISTENER_TEST_SECRET" } ],
+                "commands": []
+            }
+        "#).expect("valid profile");
+        profile.read_secrets().expect("secrets are readable");
+        let values = profile.secrets.iter().map(Secret::value).collect::<Vec<_>>();
+        assert_eq!(values, vec!["fromcredential".into(), "fromenv".into()]);
+
+        std::env::remove_var("CREDENTIALS_DIRECTORY");
+        let mut profile = serde_json::from_str::<Profile>(r#"{ "secret": { "credential": "x" }, "commands": [] }"#).unwrap();
+        assert_matches!(profile.read_secrets(), Err(ConfigError::NoCredentialsDirectory(_)));
+        let mut profile = serde_json::from_str::<Profile>(r#"{ "secret": { "env": "WEBHOOK_LISTENER_UNSET" }, "commands": [] }"#).unwrap();
+        assert_matches!(profile.read_secrets(), Err(ConfigError::MissingSecretVariable(_)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn secret_variables_are_removed() {
+        let _l = lock_env();
+        let path = std::env::temp_dir().join(format!("webhook-listener-env-{}.json", std::process::id()));
+        std::fs::write(&path, r#"
+            {
+                "secret": { "env": "WEBHOOK_LISTENER_TEST_REMOVED" },
+                "sockets": { "a": { "secret": { "env": "WEBHOOK_LISTENER_TEST_REMOVED" }, "commands": [] } },
+                "commands": []
+            }
+        "#).unwrap();
+
+        std::env::set_var("WEBHOOK_LISTENER_TEST_REMOVED", "fromenv");
+        let config = Config::from_path(&path).expect("valid config");
+        assert!(std::env::var_os("WEBHOOK_LISTENER_TEST_REMOVED").is_none());
+        assert_eq!(config.profile.secrets[0].value(), "fromenv".into());
+        assert_eq!(config.sockets["a"].secrets[0].value(), "fromenv".into());
+        assert_eq!(config.secret_variables(), vec![
+            ("WEBHOOK_LISTENER_TEST_REMOVED".to_string(), "fromenv".into()),
+            ("WEBHOOK_LISTENER_TEST_REMOVED".to_string(), "fromenv".into()),
+        ]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn secret_file_is_reloaded() {
+        let path = std::env::temp_dir().join(format!("webhook-listener-reload-{}.txt", std::process::id()));
+        std::fs::write(&path, "first\n").unwrap();
+
+        let mut secret = Secret::with_value(SecretSource::File(path.clone()), None, "");
+        secret.load().unwrap();
+        let clone = secret.clone();
+        assert_eq!(secret.value(), "first".into());
+
+        // Make sure the modification time differs, however coarse the file system's clock.
+        std::fs::write(&path, "second\n").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(clone.value(), "second".into());
+        assert_eq!(secret.value(), "second".into());
+
+        // If the file goes away, we stick with what we have.
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(secret.value(), "second".into());
+    }
+
+    #[test]
+    fn profile_without_secret_gives_error() {
+        let mut profile = serde_json::from_str::<Profile>(r#"{ "commands": [] }"#).expect("valid profile");
+        assert_matches!(profile.read_secrets(), Err(ConfigError::MissingSecret));
+
+        // With a public key, there's nothing to share.
+        let mut profile = serde_json::from_str::<Profile>(r#"{
+            "provider": { "public_key": { "key": "0cc8d3605266336fb886305ca698af34a0fd1864a795270547eead3c6ee45629", "header": "X-Signature" } },
+            "commands": []
+        }"#).expect("valid profile");
+        assert_matches!(profile.read_secrets(), Ok(()));
+    }
+
+    #[test]
+    fn deserialize_replay_config() {
+        let config = serde_json::from_str::<Config>(r#"{ "commands": [], "replay_protection": {} }"#).unwrap();
+        assert_eq!(config.replay_protection, Some(ReplayConfig {
+            ttl: Duration::from_secs(3 * 24 * 60 * 60),
+            max_deliveries: 100_000,
+            path: None,
+            allow_redeliveries: false,
+        }));
+
+        let config = serde_json::from_str::<Config>(r#"{
+            "commands": [],
+            "replay_protection": { "ttl": "1day", "max_deliveries": 500, "path": "/var/lib/webhook-listener/deliveries", "allow_redeliveries": true }
+        }"#).unwrap();
+        assert_eq!(config.replay_protection, Some(ReplayConfig {
+            ttl: Duration::from_secs(24 * 60 * 60),
+            max_deliveries: 500,
+            path: Some(PathBuf::from("/var/lib/webhook-listener/deliveries")),
+            allow_redeliveries: true,
+        }));
+    }
+
+    #[test]
+    fn replay_protection_needs_delivery_header() {
+        let dir = std::env::temp_dir().join(format!("webhook-listener-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let load = |config: &str| {
+            std::fs::write(dir.join("config.json"), config).unwrap();
+            Config::from_path(dir.join("config.json"))
+        };
+        let key = "0cc8d3605266336fb886305ca698af34a0fd1864a795270547eead3c6ee45629";
+
+        let config = format!(r#"{{
+            "provider": {{ "public_key": {{ "key": "{}", "header": "X-Signature", "delivery_header": "X-Delivery" }} }},
+            "replay_protection": {{}},
+            "commands": []
+        }}"#, key);
+        assert_matches!(load(&config), Ok(_));
+
+        let config = format!(r#"{{
+            "provider": {{ "public_key": {{ "key": "{}", "header": "X-Signature" }} }},
+            "replay_protection": {{}},
+            "commands": []
+        }}"#, key);
+        assert_matches!(load(&config), Err(ConfigError::NoDeliveryHeader(_)));
+
+        let config = format!(r#"{{
+            "sockets": {{ "a": {{ "provider": {{ "public_key": {{ "key": "{}", "header": "X-Signature" }} }}, "commands": [] }} }},
+            "replay_protection": {{}}
+        }}"#, key);
+        assert_matches!(load(&config), Err(ConfigError::NoDeliveryHeader(name)) => assert_eq!(name, "socket \"a\""));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deserialize_provider() {
+        let profile = serde_json::from_str::<Profile>(r#"{ "commands": [] }"#).unwrap();
+        assert_eq!(profile.provider, Provider::Github);
+        let profile = serde_json::from_str::<Profile>(r#"{ "provider": "gitlab", "commands": [] }"#).unwrap();
+        assert_eq!(profile.provider, Provider::Gitlab);
+        let profile = serde_json::from_str::<Profile>(r#"{ "provider": "gitea", "commands": [] }"#).unwrap();
+        assert_eq!(profile.provider, Provider::Forgejo);
+        let profile = serde_json::from_str::<Profile>(r#"{ "provider": "standard_webhooks", "timestamp_tolerance": "1m", "commands": [] }"#).unwrap();
+        assert_eq!(profile.provider, Provider::StandardWebhooks);
+        assert_eq!(profile.timestamp_tolerance, Duration::from_secs(60));
+        assert!(serde_json::from_str::<Profile>(r#"{ "provider": "bitbucket", "commands": [] }"#).is_err());
+    }
+
+    #[test]
+    fn deserialize_max_event_age() {
+        let profile = serde_json::from_str::<Profile>(r#"{ "secret_path": "/path/to/secret.txt", "commands": [] }"#).unwrap();
+        assert_eq!(profile.max_event_age, None);
+        assert!(!profile.reject_undated_events);
+
+        let profile = serde_json::from_str::<Profile>(r#"
+            { "secret_path": "/path/to/secret.txt", "max_event_age": "1h", "reject_undated_events": true, "commands": [] }
+        "#).unwrap();
+        assert_eq!(profile.max_event_age, Some(Duration::from_secs(60 * 60)));
+        assert!(profile.reject_undated_events);
+    }
+
+    #[test]
+    fn secret_is_not_printed() {
+        let profile = Profile {
+            secret_path: None,
+            secret: None,
+            secrets: vec![Secret::with_value(SecretSource::Env("SECRET".to_string()), None, "mysecret")],
+            repository_secrets: HashMap::new(),
+            provider: Provider::Github,
+            allow_sha1: false,
+            timestamp_tolerance: Duration::from_secs(5 * 60),
+            max_event_age: None,
+            reject_undated_events: false,
+            commands: vec![],
+        };
+        let printed = format!("{:?}", profile);
+        assert!(!printed.contains("mysecret"), "{}", printed);
+        assert!(printed.contains("[redacted]"), "{}", printed);
+    }
+
+    #[test]
+    fn deserialize_tls_config() {
+        let config_json = r#"
+            {
+                "secret_path": "/path/to/secret.txt",
+                "commands": [ ],
+                "tls": {
+                    "cert_path": "/etc/ssl/fullchain.pem",
+                    "key_path": "/etc/ssl/privkey.pem"
+                }
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        let expected_tls = TlsConfig {
+            cert_path: PathBuf::from("/etc/ssl/fullchain.pem"),
+            key_path: PathBuf::from("/etc/ssl/privkey.pem"),
+            client_ca_path: None,
+            allowed_clients: vec![],
+        };
+        assert_eq!(parsed_config.tls, Some(expected_tls));
+
+        let config_json = r#"
+            {
+                "secret_path": "/path/to/secret.txt",
+                "commands": [
+                    {
+                        "event": "push",
+                        "command": "/usr/bin/deploy",
+                        "identities": ["deploy-bot"]
+                    }
+                ],
+                "tls": {
+                    "cert_path": "/etc/ssl/fullchain.pem",
+                    "key_path": "/etc/ssl/privkey.pem",
+                    "client_ca_path": "/etc/ssl/clients.pem",
+                    "allowed_clients": ["deploy-bot", "ci.internal"]
+                }
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        let tls = parsed_config.tls.expect("tls is set");
+        assert_eq!(tls.client_ca_path, Some(PathBuf::from("/etc/ssl/clients.pem")));
+        assert_eq!(tls.allowed_clients, vec!["deploy-bot".to_string(), "ci.internal".to_string()]);
+        assert_eq!(parsed_config.profile.commands[0].identities, vec!["deploy-bot".to_string()]);
+    }
+
+    #[test]
+    fn deserialize_http_config() {
+        let config_json = r#"
+            {
+                "secret_path": "/path/to/secret.txt",
+                "commands": [ ],
+                "shutdown_grace_period": "5min",
+                "http": {
+                    "keep_alive": true,
+                    "header_read_timeout": "10s",
+                    "keep_alive_interval": "1min",
+                    "idle_timeout": "2min"
+                }
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        assert_eq!(parsed_config.shutdown_grace_period, Duration::from_secs(300));
+
+        let expected_http = HttpConfig {
+            keep_alive: true,
+            header_read_timeout: Duration::from_secs(10),
+            keep_alive_interval: Some(Duration::from_secs(60)),
+            idle_timeout: Duration::from_secs(120),
+        };
+        assert_eq!(parsed_config.http, expected_http);
+
+        let parsed_http = serde_json::from_str::<HttpConfig>("{}").expect("valid config");
+        assert_eq!(parsed_http, HttpConfig::default());
+        assert_eq!(parsed_http.header_read_timeout, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn deserialize_listen_addresses() {
+        let config_json = r#"
+            {
+                "secret_path": "/path/to/secret.txt",
+                "listen": "tcp:0.0.0.0:8080",
+                "commands": [ ]
+            }
+        "#;
+        let parsed_config = serde_json::from_str::<Config>(config_json).expect("valid config");
+        assert_eq!(parsed_config.listen, Some(ListenAddr::Tcp("0.0.0.0:8080".parse().unwrap())));
+
+        assert_eq!("tcp:[::1]:8080".parse(), Ok(ListenAddr::Tcp("[::1]:8080".parse().unwrap())));
+        assert_eq!("unix:/run/wh.sock".parse(), Ok(ListenAddr::Unix(PathBuf::from("/run/wh.sock"))));
+    }
+
+    #[test]
+    fn invalid_listen_address_gives_error() {
+        assert_contains!("0.0.0.0:8080".parse::<ListenAddr>().unwrap_err(), "expected `tcp:<ip>:<port>` or `unix:<path>`");
+        assert_contains!("tcp:localhost".parse::<ListenAddr>().unwrap_err(), "invalid TCP address");
+        assert_contains!("unix:".parse::<ListenAddr>().unwrap_err(), "missing path");
+
+        let config_json = r#"
+            {
+                "secret_path": "/path/to/secret.txt",
+                "listen": "udp:0.0.0.0:8080",
+                "commands": [ ]
+            }
+        "#;
+        let err = serde_json::from_str::<Config>(config_json).unwrap_err();
+        assert_contains!(err, "invalid listen address");
+    }
 }
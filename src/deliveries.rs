@@ -0,0 +1,262 @@
+//! Remembering which deliveries have been received, so a captured request can't simply be sent
+//! again to run the commands another time.
+//!
+//! Note that the delivery ID is not covered by the signature, so this only stops replays which
+//! leave the request untouched. Anyone who captured a request can send it again with made up IDs,
+//! which is why the number of deliveries remembered is limited.
+
+use crate::config::ReplayConfig;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The IDs of the deliveries received within the configured TTL, optionally backed by a file.
+///
+/// At most `max_deliveries` are remembered, after which the oldest ones are forgotten early.
+///
+/// The file has one line per delivery, consisting of the time it was received (in seconds since
+/// the Unix epoch) and its ID, separated by a space. New deliveries are appended, and expired ones
+/// are dropped whenever the file is rewritten.
+pub struct DeliveryStore {
+    ttl: Duration,
+    max_deliveries: usize,
+    path: Option<PathBuf>,
+    allow_redeliveries: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    received: HashMap<String, SystemTime>,
+
+    /// The same deliveries, ordered by when they were received.
+    by_time: BTreeSet<(SystemTime, String)>,
+
+    /// The number of lines in the file, including those of expired deliveries.
+    lines: usize,
+}
+
+impl DeliveryStore {
+    /// Creates a store with the settings from `config`, reading the deliveries received before
+    /// from its file.
+    pub fn open(config: &ReplayConfig, now: SystemTime) -> io::Result<DeliveryStore> {
+        let mut received = HashMap::new();
+        if let Some(path) = &config.path {
+            match File::open(path) {
+                Ok(file) => received = read_deliveries(file)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        let by_time = received.iter().map(|(id, received)| (*received, id.clone())).collect();
+        let store = DeliveryStore {
+            ttl: config.ttl,
+            max_deliveries: config.max_deliveries,
+            path: config.path.clone(),
+            allow_redeliveries: config.allow_redeliveries,
+            state: Mutex::new(State { received, by_time, lines: 0 }),
+        };
+        let mut state = store.state.lock().unwrap();
+        store.expire(&mut state, now);
+        store.forget_oldest(&mut state, store.max_deliveries);
+        store.rewrite(&mut state)?;
+        drop(state);
+        Ok(store)
+    }
+
+    /// Records that the delivery `id` was received at `now`. Returns `false` if it had been
+    /// received before, within the TTL.
+    pub fn insert(&self, id: &str, now: SystemTime) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state, now);
+        if state.received.contains_key(id) {
+            return false;
+        }
+        if state.received.len() >= self.max_deliveries {
+            self.forget_oldest(&mut state, self.max_deliveries - 1);
+        }
+        state.received.insert(id.to_string(), now);
+        state.by_time.insert((now, id.to_string()));
+
+        // Losing the file only means we forget the deliveries on restart, so that's not a reason
+        // to reject the request.
+        if let Err(e) = self.persist(&mut state, id, now) {
+            eprintln!("Failed to save delivery ID to {}: {}", self.path.as_ref().unwrap().display(), e);
+        }
+        true
+    }
+
+    /// Whether deliveries received before should be accepted anyway.
+    pub fn allow_redeliveries(&self) -> bool {
+        self.allow_redeliveries
+    }
+
+    fn expire(&self, state: &mut State, now: SystemTime) {
+        while let Some((received, id)) = state.by_time.first() {
+            if received.checked_add(self.ttl).is_none_or(|expiry| expiry > now) {
+                break;
+            }
+            state.received.remove(id);
+            state.by_time.pop_first();
+        }
+    }
+
+    /// Forgets the oldest deliveries until at most `keep` are left.
+    fn forget_oldest(&self, state: &mut State, keep: usize) {
+        let mut forgotten = 0;
+        while state.received.len() > keep {
+            let Some((_, id)) = state.by_time.pop_first() else {
+                break;
+            };
+            state.received.remove(&id);
+            forgotten += 1;
+        }
+        if forgotten > 0 {
+            eprintln!("warning: forgot {} delivery ID(s) early, since only {} are remembered",
+                      forgotten, self.max_deliveries);
+        }
+    }
+
+    fn persist(&self, state: &mut State, id: &str, now: SystemTime) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // Every now and then, the file is rewritten so it doesn't grow forever.
+        if state.lines >= 2 * state.received.len() + 100 {
+            return self.rewrite(state);
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(format_delivery(id, now).as_bytes())?;
+        state.lines += 1;
+        Ok(())
+    }
+
+    /// Replaces the file with one containing only the deliveries in `state`.
+    fn rewrite(&self, state: &mut State) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = state.by_time.iter()
+            .map(|(received, id)| format_delivery(id, *received))
+            .collect::<String>();
+        // Writing to a temporary file first makes sure we never leave a truncated file behind.
+        let tmp = tmp_path(path);
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)?;
+        state.lines = state.received.len();
+        Ok(())
+    }
+}
+
+fn read_deliveries(file: File) -> io::Result<HashMap<String, SystemTime>> {
+    let mut received = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // Lines which can't be parsed, e.g. because the last write was interrupted or the file is
+        // corrupt, are skipped.
+        let Some((secs, id)) = line.split_once(' ') else {
+            continue;
+        };
+        let Some(received_at) = secs.parse().ok().and_then(|secs| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))) else {
+            continue;
+        };
+        received.insert(id.to_string(), received_at);
+    }
+    Ok(received)
+}
+
+fn format_delivery(id: &str, received: SystemTime) -> String {
+    let secs = received.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{} {}\n", secs, id)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeliveryStore;
+    use crate::config::ReplayConfig;
+    use std::time::{Duration, SystemTime};
+
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn repeated_deliveries() {
+        let config = ReplayConfig { ttl: TTL, max_deliveries: 1000, path: None, allow_redeliveries: false };
+        let now = SystemTime::now();
+        let store = DeliveryStore::open(&config, now).unwrap();
+        assert!(store.insert("72d3162e-cc78-11e3-81ab-4c9367dc0958", now));
+        assert!(store.insert("a2c8f8d0-cc78-11e3-81ab-4c9367dc0958", now));
+        assert!(!store.insert("72d3162e-cc78-11e3-81ab-4c9367dc0958", now + Duration::from_secs(1)));
+
+        // Once the TTL has passed, the delivery is forgotten.
+        assert!(store.insert("72d3162e-cc78-11e3-81ab-4c9367dc0958", now + TTL));
+    }
+
+    #[test]
+    fn deliveries_are_persisted() {
+        let path = std::env::temp_dir().join(format!("webhook-listener-test-{}-deliveries", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = ReplayConfig { ttl: TTL, max_deliveries: 1000, path: Some(path.clone()), allow_redeliveries: false };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let store = DeliveryStore::open(&config, now).unwrap();
+        assert!(store.insert("first", now));
+        assert!(store.insert("second", now + TTL / 2));
+        drop(store);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "1700000000 first\n1700001800 second\n");
+
+        // After a restart, the deliveries are still known. Expired ones and garbage are dropped.
+        std::fs::write(&path, contents + "garbage\n18446744073709551615 huge\n9223372036854775807 max\n17000").unwrap();
+        let store = DeliveryStore::open(&config, now + TTL).unwrap();
+        assert!(!store.insert("second", now + TTL));
+        assert!(store.insert("first", now + TTL));
+        drop(store);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "1700001800 second\n9223372036854775807 max\n1700003600 first\n");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_is_compacted() {
+        let path = std::env::temp_dir().join(format!("webhook-listener-test-{}-compacted", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = ReplayConfig { ttl: Duration::from_secs(10), max_deliveries: 1000, path: Some(path.clone()), allow_redeliveries: false };
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let store = DeliveryStore::open(&config, start).unwrap();
+        for i in 0..1000 {
+            assert!(store.insert(&i.to_string(), start + Duration::from_secs(i)));
+        }
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < 200, "{} lines", lines);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn oldest_deliveries_are_forgotten() {
+        let config = ReplayConfig { ttl: TTL, max_deliveries: 2, path: None, allow_redeliveries: false };
+        let now = SystemTime::now();
+        let store = DeliveryStore::open(&config, now).unwrap();
+        assert!(store.insert("first", now));
+        assert!(store.insert("second", now + Duration::from_secs(1)));
+        assert!(store.insert("third", now + Duration::from_secs(2)));
+        assert!(!store.insert("third", now + Duration::from_secs(3)));
+        assert!(!store.insert("second", now + Duration::from_secs(3)));
+        assert_eq!(store.state.lock().unwrap().received.len(), 2);
+
+        // The first delivery made room for the third one.
+        assert!(store.insert("first", now + Duration::from_secs(3)));
+    }
+}
@@ -0,0 +1,174 @@
+//! Tracking of when the server was last active and of the work still in flight. This is used to
+//! implement [`max_idle_time`](crate::config::Config::max_idle_time) across all the sockets the
+//! server is listening on, and to drain connections and commands before exiting.
+
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Keeps track of the last time the server did something useful, and of how many connections and
+/// commands are currently in flight.
+///
+/// A single tracker is shared between all accept loops, so the server only shuts down once _all_
+/// sockets have been idle for long enough.
+pub struct IdleTracker {
+    state: Mutex<State>,
+
+    /// Notified whenever the last piece of in-flight work finishes.
+    done: Notify,
+}
+
+struct State {
+    last_activity: Instant,
+    busy: usize,
+}
+
+impl IdleTracker {
+    pub fn new() -> IdleTracker {
+        IdleTracker {
+            state: Mutex::new(State {
+                last_activity: Instant::now(),
+                busy: 0,
+            }),
+            done: Notify::new(),
+        }
+    }
+
+    /// Marks the server as busy until the returned guard is dropped. This is used for connections
+    /// and commands, which [`wait_done`](IdleTracker::wait_done) waits for.
+    pub fn busy(self: &Arc<Self>) -> BusyGuard {
+        let mut state = self.state.lock().unwrap();
+        state.last_activity = Instant::now();
+        state.busy += 1;
+        BusyGuard { tracker: self.clone() }
+    }
+
+    /// Returns the number of [`BusyGuard`]s currently alive.
+    pub fn busy_count(&self) -> usize {
+        self.state.lock().unwrap().busy
+    }
+
+    /// Waits until all [`BusyGuard`]s have been dropped.
+    pub async fn wait_done(&self) {
+        loop {
+            // The future must be created before checking, so we don't miss a notification sent in
+            // between.
+            let done = self.done.notified();
+            if self.state.lock().unwrap().busy == 0 {
+                return;
+            }
+            done.await;
+        }
+    }
+
+    /// Waits until the server has been idle for `max_idle_time`, i.e. until that much time has
+    /// passed since the last [`BusyGuard`] was dropped. The server is never idle while a guard is
+    /// alive.
+    pub async fn wait_idle(&self, max_idle_time: Duration) {
+        loop {
+            let done = self.done.notified();
+            let deadline = {
+                let state = self.state.lock().unwrap();
+                (state.busy == 0).then_some(state.last_activity + max_idle_time)
+            };
+            match deadline {
+                // The clock only starts once all connections and commands are done.
+                None => done.await,
+                Some(deadline) if deadline <= Instant::now() => return,
+                // More work may start (and finish) while we sleep, in which case we just go around
+                // again.
+                Some(deadline) => time::sleep_until(deadline).await,
+            }
+        }
+    }
+}
+
+/// Keeps the [`IdleTracker`] it came from busy until dropped.
+pub struct BusyGuard {
+    tracker: Arc<IdleTracker>,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        let mut state = self.tracker.state.lock().unwrap();
+        state.last_activity = Instant::now();
+        state.busy -= 1;
+        if state.busy == 0 {
+            self.tracker.done.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdleTracker;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::{self, Instant};
+
+    #[tokio::test]
+    async fn wait_idle_without_activity() {
+        let tracker = IdleTracker::new();
+        let start = Instant::now();
+        tracker.wait_idle(Duration::from_millis(50)).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn short_work_postpones_idle() {
+        let tracker = Arc::new(IdleTracker::new());
+        let start = Instant::now();
+
+        let worker = {
+            let tracker = tracker.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_millis(60)).await;
+                drop(tracker.busy());
+            })
+        };
+
+        tracker.wait_idle(Duration::from_millis(100)).await;
+        worker.await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(160));
+    }
+
+    #[tokio::test]
+    async fn never_idle_while_busy() {
+        let tracker = Arc::new(IdleTracker::new());
+        let start = Instant::now();
+
+        // This work takes much longer than the idle time, so idle time should be counted from
+        // when it finishes.
+        let guard = tracker.busy();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            drop(guard);
+        });
+
+        tracker.wait_idle(Duration::from_millis(50)).await;
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn wait_done_waits_for_guards() {
+        let tracker = Arc::new(IdleTracker::new());
+        tracker.wait_done().await; // Nothing in flight.
+
+        let guard1 = tracker.busy();
+        let guard2 = tracker.busy();
+        assert_eq!(tracker.busy_count(), 2);
+
+        let start = Instant::now();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            drop(guard1);
+            time::sleep(Duration::from_millis(30)).await;
+            drop(guard2);
+        });
+        tracker.wait_done().await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(tracker.busy_count(), 0);
+    }
+}
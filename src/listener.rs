@@ -0,0 +1,185 @@
+//! This module abstracts over the different kinds of sockets the server can accept connections
+//! on, so that the accept loop in `main.rs` doesn't have to care whether it was handed a UNIX
+//! socket by systemd or bound a TCP socket itself.
+
+use crate::config::ListenAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use std::fs;
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A listening socket of any of the supported families.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds a new listening socket at the given address.
+    pub async fn bind(addr: &ListenAddr) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            },
+        }
+    }
+
+    /// Checks whether the socket is bound to `addr`, e.g. for a socket from a previous process.
+    pub fn is_bound_to(&self, addr: &ListenAddr) -> bool {
+        match (self, addr) {
+            (Listener::Tcp(listener), ListenAddr::Tcp(addr)) => {
+                listener.local_addr().is_ok_and(|local| local == *addr)
+            },
+            (Listener::Unix(listener), ListenAddr::Unix(path)) => {
+                listener.local_addr().is_ok_and(|local| local.as_pathname() == Some(path))
+            },
+            _ => false,
+        }
+    }
+
+    /// Accepts a new incoming connection.
+    pub async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await.map(|(s, _)| Stream::Tcp(s)),
+            Listener::Unix(listener) => listener.accept().await.map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+/// Removes the socket file at `path` if it was left behind by a previous run. Nothing is removed
+/// while another process still accepts connections on it, or if it isn't a socket at all, in which
+/// case binding fails as usual.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Ok(());
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            println!("Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        },
+        _ => Ok(()),
+    }
+}
+
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) => listener.as_fd(),
+            Listener::Unix(listener) => listener.as_fd(),
+        }
+    }
+}
+
+/// A connection accepted by a [`Listener`].
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Listener;
+    use crate::config::ListenAddr;
+    use crate::systemd_socket::tests::lock_env;
+
+    #[test]
+    fn is_bound_to() {
+        // Other tests expect sockets to get specific fds, so we must not create any concurrently.
+        let _l = lock_env();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        let _guard = runtime.enter();
+        let path = std::env::temp_dir().join(format!("webhook-listener-bound-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let addr = ListenAddr::Unix(path.clone());
+        let listener = runtime.block_on(Listener::bind(&addr)).unwrap();
+        assert!(listener.is_bound_to(&addr));
+        assert!(!listener.is_bound_to(&ListenAddr::Unix("/run/other.sock".into())));
+        assert!(!listener.is_bound_to(&"tcp:127.0.0.1:8080".parse().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+
+        let listener = runtime.block_on(Listener::bind(&"tcp:127.0.0.1:0".parse().unwrap())).unwrap();
+        let Listener::Tcp(tcp) = &listener else { unreachable!() };
+        let addr = ListenAddr::Tcp(tcp.local_addr().unwrap());
+        assert!(listener.is_bound_to(&addr));
+        assert!(!listener.is_bound_to(&"tcp:127.0.0.1:1".parse().unwrap()));
+    }
+
+    #[test]
+    fn stale_socket_is_replaced() {
+        let _l = lock_env();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        let _guard = runtime.enter();
+        let path = std::env::temp_dir().join(format!("webhook-listener-stale-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let addr = ListenAddr::Unix(path.clone());
+
+        // The socket file stays around after the listener is closed, like after a previous run.
+        drop(runtime.block_on(Listener::bind(&addr)).unwrap());
+        assert!(path.exists());
+        let listener = runtime.block_on(Listener::bind(&addr)).unwrap();
+
+        // A socket somebody is listening on is left alone.
+        assert!(runtime.block_on(Listener::bind(&addr)).is_err());
+        drop(listener);
+
+        // So is anything that isn't a socket.
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(runtime.block_on(Listener::bind(&addr)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,68 +1,415 @@
 // The systemd_socket module contains a lot of dead code which is only used in tests, but which I
 // would like to keep up to date in case I need the module for another project.
 #[allow(dead_code)]
-
 mod systemd_socket;
 mod service;
 mod config;
+mod listener;
+mod idle;
+mod tls;
+mod status;
+mod watchdog;
+mod reexec;
+mod secret;
+mod provider;
+mod public_key;
+mod deliveries;
+#[cfg(test)]
+mod test_util;
+
+use config::{HttpConfig, ListenAddr, Profile, ReplayConfig, TlsConfig};
+use tls::PeerIdentity;
+use deliveries::DeliveryStore;
+use idle::{BusyGuard, IdleTracker};
+use status::Status;
+use watchdog::Watchdog;
+use reexec::Handoff;
+use listener::{Listener, Stream};
 
 use hyper::Request;
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
 
-use std::os::unix::net::UnixListener as StdUnixListener;
-use tokio::net::UnixListener as TokioUnixListener;
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
+use tokio::net::{UnixListener as TokioUnixListener, UnixStream as TokioUnixStream};
+use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::io;
 use std::process;
 use std::path::Path;
 use std::env;
+use std::time::SystemTime;
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [--listen <tcp:ADDR:PORT|unix:PATH>] <path/to/config.json>", program);
+    process::exit(1);
+}
 
 fn load_config() -> config::Config {
     let args = env::args().collect::<Vec<_>>();
-    if args.len() != 2 {
-        eprintln!("Too {} command line arguments", if args.len() < 2 { "few" } else { "many" });
-        eprintln!("Usage: {} <path/to/config.json>", args[0]);
-        process::exit(1);
+
+    // Options may appear anywhere, but there must be exactly one positional argument left over.
+    let mut listen = None;
+    let mut positional = vec![];
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--listen" {
+            let Some(value) = rest.next() else {
+                eprintln!("Missing value for --listen");
+                usage(&args[0]);
+            };
+            match value.parse::<config::ListenAddr>() {
+                Ok(addr) => listen = Some(addr),
+                Err(e) => {
+                    eprintln!("Error parsing --listen: {}", e);
+                    process::exit(1);
+                },
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+    if positional.len() != 1 {
+        eprintln!("Too {} command line arguments", if positional.is_empty() { "few" } else { "many" });
+        usage(&args[0]);
     }
 
-    let config_path = Path::new(&args[1]);
-    match config::Config::from_path(config_path) {
+    let config_path = Path::new(positional[0]);
+    let mut config = match config::Config::from_path(config_path) {
         Ok(config) => config,
         Err(e) => {
             eprintln!("Error reading configuration: {}", e);
             process::exit(1);
         },
+    };
+
+    // The command line takes precedence over the configuration file.
+    if listen.is_some() {
+        config.listen = listen;
+    }
+    if config.listen.is_some() && config.profile.is_empty() && !config.sockets.is_empty() {
+        eprintln!("The socket bound with `listen` uses the settings at the top level of the configuration, which are missing.");
+        process::exit(1);
     }
+
+    config
 }
 
-fn get_listener_from_systemd() -> io::Result<TokioUnixListener> {
+/// The name under which the single socket is passed on to a new process, when not using named
+/// sockets.
+const LISTEN_SOCKET_NAME: &str = "listen";
+
+/// A socket passed from systemd without a name.
+enum Activated {
+    /// A listening socket, i.e. `Accept=no` (the default).
+    Listener(Listener),
+
+    /// A single connection, with `Accept=yes`. systemd starts a new instance for every connection.
+    Connection(Stream),
+}
+
+fn get_socket_from_systemd() -> io::Result<Activated> {
     let mut fds = systemd_socket::listen_fds(true).unwrap_or(vec![]);
     if fds.len() != 1 {
-        eprintln!("Too {} sockets passed from systemd", if fds.len() < 1 { "few" } else { "many" });
-        eprintln!("This tool only works with systemd socket activation.");
+        eprintln!("Too {} sockets passed from systemd", if fds.is_empty() { "few" } else { "many" });
+        eprintln!("Use systemd socket activation or pass --listen to bind a socket directly.");
         process::exit(1);
     }
     let fd = fds.remove(0);
+    if is_connected(&fd) {
+        Ok(Activated::Connection(stream_from_fd(fd)?))
+    } else {
+        Ok(Activated::Listener(listener_from_fd(fd)?))
+    }
+}
 
-    // See note inside `systemd_socket::is_socket_internal` for why this is broken on Darwin.
-    #[cfg(not(target_vendor = "apple"))] // See note in `is_socket_unix`.
-    {
-        use nix::sys::socket::SockType;
+/// Gets all the sockets passed from systemd, pairing each of them with its name and the profile
+/// matching it.
+fn get_named_listeners_from_systemd(
+    profiles: &HashMap<String, Profile>,
+) -> io::Result<Vec<(String, Listener, Profile)>> {
+    let fds = match systemd_socket::listen_fds_with_names(true) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("Failed to get named sockets from systemd: {}", e);
+            eprintln!("Configuring `sockets` requires systemd socket activation with named sockets.");
+            process::exit(1);
+        },
+    };
+    if fds.is_empty() {
+        eprintln!("Too few sockets passed from systemd");
+        process::exit(1);
+    }
 
-        if !systemd_socket::is_socket_unix(&fd, Some(SockType::Stream), Some(true), None)
-            .unwrap_or(false)
-        {
-            eprintln!("The socket from systemd is not a streaming UNIX socket");
+    let mut listeners = Vec::with_capacity(fds.len());
+    for (name, fd) in fds {
+        let Some(profile) = profiles.get(&name) else {
+            let mut known = profiles.keys().map(String::as_str).collect::<Vec<_>>();
+            known.sort();
+            eprintln!("Unknown socket name {:?} passed from systemd", name);
+            eprintln!("Expected one of the names in `sockets`: {}", known.join(", "));
             process::exit(1);
-        }
+        };
+        println!("Using socket {:?} from systemd", name);
+        let profile = profile.clone();
+        listeners.push((name, listener_from_fd(fd)?, profile));
     }
+    Ok(listeners)
+}
 
-    let std_listener = StdUnixListener::from(fd);
-    std_listener.set_nonblocking(true)?; // Required by tokio::net::UnixListener::from_std().
+/// Gets the socket passed on by the process we replaced (see [`reexec`]) or kept in systemd's file
+/// descriptor store, if any. In standalone mode, we use it instead of binding the socket again.
+fn get_inherited_listener(addr: &ListenAddr) -> io::Result<Option<Listener>> {
+    let Ok(mut fds) = systemd_socket::listen_fds_with_names(true) else {
+        return Ok(None);
+    };
+    let Some(fd) = fds.remove(LISTEN_SOCKET_NAME) else {
+        return Ok(None);
+    };
+    let listener = listener_from_fd(fd)?;
+    if listener.is_bound_to(addr) {
+        return Ok(Some(listener));
+    }
+
+    // The address was changed in the meantime, so the stored socket is of no use anymore.
+    println!("Discarding socket from previous process, which isn't bound to {}", addr);
+    let remove = format!("FDSTOREREMOVE=1\nFDNAME={}", LISTEN_SOCKET_NAME);
+    if let Err(e) = systemd_socket::notify(false, &remove) {
+        eprintln!("Failed to remove socket from systemd's file descriptor store: {}", e);
+    }
+    Ok(None)
+}
+
+/// Stores the socket we are listening on in standalone mode with systemd, so a restarted service
+/// gets it back through [`get_inherited_listener`] and no connections are refused in between.
+/// Does nothing outside of systemd, and systemd ignores it unless `FileDescriptorStoreMax=` is set.
+fn store_listener(listener: &Listener) {
+    let state = format!("FDSTORE=1\nFDNAME={}", LISTEN_SOCKET_NAME);
+    if let Err(e) = systemd_socket::notify_with_fds(false, &state, &[listener.as_fd().as_raw_fd()]) {
+        eprintln!("Failed to store socket with systemd: {}", e);
+    }
+}
 
-    let tokio_listener = TokioUnixListener::from_std(std_listener)?;
-    Ok(tokio_listener)
+/// Wraps a listening socket passed from systemd in a [`Listener`], detecting whether it is a UNIX
+/// or an Internet (IPv4/IPv6) socket.
+fn listener_from_fd(fd: OwnedFd) -> io::Result<Listener> {
+    use nix::sys::socket::SockType;
+
+    // See note inside `systemd_socket::is_socket_internal` for why we cannot check whether the
+    // socket is listening on Darwin.
+    let listening = if cfg!(target_vendor = "apple") { None } else { Some(true) };
+
+    if systemd_socket::is_socket_unix(&fd, Some(SockType::Stream), listening, None).unwrap_or(false) {
+        let std_listener = StdUnixListener::from(fd);
+        std_listener.set_nonblocking(true)?; // Required by tokio::net::UnixListener::from_std().
+
+        let tokio_listener = TokioUnixListener::from_std(std_listener)?;
+        Ok(Listener::Unix(tokio_listener))
+    } else if systemd_socket::is_socket_inet(&fd, None, Some(SockType::Stream), listening, None).unwrap_or(false) {
+        let std_listener = StdTcpListener::from(fd);
+        std_listener.set_nonblocking(true)?; // Required by tokio::net::TcpListener::from_std().
+
+        let tokio_listener = TokioTcpListener::from_std(std_listener)?;
+        Ok(Listener::Tcp(tokio_listener))
+    } else {
+        eprintln!("The socket from systemd is neither a streaming UNIX socket nor a TCP socket");
+        process::exit(1);
+    }
+}
+
+/// Checks whether `fd` is a connected stream socket rather than a listening one, as passed by
+/// systemd with `Accept=yes`.
+fn is_connected(fd: &OwnedFd) -> bool {
+    use nix::sys::socket::SockType;
+
+    // We cannot tell on Darwin (see `listener_from_fd`), but there is no systemd there anyway.
+    if cfg!(target_vendor = "apple") {
+        return false;
+    }
+    systemd_socket::is_socket_unix(fd, Some(SockType::Stream), Some(false), None).unwrap_or(false)
+        || systemd_socket::is_socket_inet(fd, None, Some(SockType::Stream), Some(false), None).unwrap_or(false)
+}
+
+/// Wraps a connected socket passed from systemd in a [`Stream`].
+fn stream_from_fd(fd: OwnedFd) -> io::Result<Stream> {
+    use nix::sys::socket::SockType;
+
+    if systemd_socket::is_socket_unix(&fd, Some(SockType::Stream), None, None).unwrap_or(false) {
+        let std_stream = StdUnixStream::from(fd);
+        std_stream.set_nonblocking(true)?; // Required by tokio::net::UnixStream::from_std().
+        Ok(Stream::Unix(TokioUnixStream::from_std(std_stream)?))
+    } else {
+        let std_stream = StdTcpStream::from(fd);
+        std_stream.set_nonblocking(true)?; // Required by tokio::net::TcpStream::from_std().
+        Ok(Stream::Tcp(TokioTcpStream::from_std(std_stream)?))
+    }
+}
+
+/// Sets up TLS termination as described by `tls_config`, exiting if the certificate cannot be
+/// loaded.
+fn setup_tls(tls_config: &TlsConfig) -> TlsAcceptor {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let acceptor = tls::CertReloader::new(tls_config, provider.clone())
+        .map(Arc::new)
+        .and_then(|reloader| {
+            let acceptor = tls::acceptor(tls_config, reloader.clone(), provider)?;
+            tokio::spawn(async move {
+                if let Err(e) = reloader.watch().await {
+                    eprintln!("Unable to watch TLS certificate for changes: {}", e);
+                }
+            });
+            Ok(acceptor)
+        });
+    match acceptor {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            eprintln!("Error setting up TLS: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+/// Sets up the store of delivery IDs described by `replay_config`, exiting if its file cannot be
+/// read or written.
+fn open_delivery_store(replay_config: &ReplayConfig) -> Arc<DeliveryStore> {
+    match DeliveryStore::open(replay_config, SystemTime::now()) {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            let path = replay_config.path.as_ref().expect("only the file can fail");
+            eprintln!("Error opening delivery store {}: {}", path.display(), e);
+            process::exit(1);
+        },
+    }
+}
+
+/// State shared between all accept loops and the connections they serve.
+struct Shared {
+    /// A single tracker is shared by all accept loops, so we only exit once all of them are idle.
+    /// Every connection and every running command keeps it busy.
+    idle: Arc<IdleTracker>,
+    status: Arc<Status>,
+    watchdog: Arc<Watchdog>,
+    tls: Option<TlsAcceptor>,
+    http: HttpConfig,
+    deliveries: Option<Arc<DeliveryStore>>,
+
+    /// Set to `true` when the server starts draining. Open connections finish the request they are
+    /// serving and then close.
+    shutdown: watch::Sender<bool>,
+}
+
+/// Continuously accepts incoming connections on `listener`, serving each of them with `profile`.
+///
+/// This only ever returns if accepting a connection fails.
+async fn accept_loop(listener: Listener, profile: Arc<Profile>, shared: Arc<Shared>) -> io::Result<()> {
+    let mut heartbeat = shared.watchdog.register();
+    loop {
+        let stream = tokio::select! {
+            stream = listener.accept() => stream?,
+            _ = heartbeat.answer() => continue,
+        };
+        let busy = shared.idle.busy();
+
+        // Spawn a tokio task to serve multiple connections concurrently. The TLS handshake also
+        // happens in there, so a slow client cannot hold up the accept loop.
+        tokio::task::spawn(handle_connection(stream, profile.clone(), shared.clone(), busy));
+    }
+}
+
+/// Serves a connection accepted by an accept loop or passed from systemd, doing the TLS handshake
+/// first if configured. `busy` is dropped once the connection is closed.
+///
+/// The handshake has to be done within the [header read
+/// timeout](HttpConfig::header_read_timeout), so a client which never finishes it can't keep the
+/// server busy forever.
+async fn handle_connection(stream: Stream, profile: Arc<Profile>, shared: Arc<Shared>, busy: BusyGuard) {
+    let _busy = busy;
+    match &shared.tls {
+        Some(acceptor) => match tokio::time::timeout(shared.http.header_read_timeout, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+                let peer = PeerIdentity::from_connection(stream.get_ref().1);
+                serve_connection(stream, profile, peer, &shared).await
+            },
+            Ok(Err(err)) => eprintln!("Error during TLS handshake: {}", err),
+            Err(_) => eprintln!("TLS handshake did not finish within {}, closing connection.",
+                                humantime::format_duration(shared.http.header_read_timeout)),
+        },
+        None => serve_connection(stream, profile, None, &shared).await,
+    }
+}
+
+/// Serves HTTP/1 or HTTP/2 on a single connection until the client is done with it. `peer` is the
+/// identity of the client, if it authenticated with a TLS client certificate.
+async fn serve_connection<S>(stream: S, profile: Arc<Profile>, peer: Option<PeerIdentity>, shared: &Arc<Shared>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+
+    // Requests keep the connection busy, so we can tell when a connection kept alive has been
+    // sitting idle between requests for too long.
+    let conn_idle = Arc::new(IdleTracker::new());
+    let service = {
+        let shared = shared.clone();
+        let conn_idle = conn_idle.clone();
+        service_fn(move |req: Request<hyper::body::Incoming>| {
+            let busy = conn_idle.busy();
+            let profile = profile.clone();
+            let peer = peer.clone();
+            let idle = shared.idle.clone();
+            let status = shared.status.clone();
+            let deliveries = shared.deliveries.clone();
+            async move {
+                let _busy = busy;
+                service::router(req, &profile, peer.as_ref(), &idle, &status, deliveries.as_deref()).await
+            }
+        })
+    };
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1()
+        // On OSX, keep alive must be disabled. Otherwise serve_connection blocks and later returns
+        // an `Err` derived from `ENOTCONN`. That's why it's disabled by default.
+        .keep_alive(shared.http.keep_alive)
+        .timer(TokioTimer::new())
+        .header_read_timeout(shared.http.header_read_timeout);
+    builder.http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(shared.http.keep_alive_interval);
+
+    let conn = builder.serve_connection(io, service);
+    tokio::pin!(conn);
+
+    // If the server starts draining, we let the connection finish the request in progress (if any)
+    // but don't accept any more requests on it.
+    // The same goes for connections which have been idle for too long. Otherwise they would keep
+    // the server from ever becoming idle itself.
+    let mut shutdown = shared.shutdown.subscribe();
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        // The guard returned by `wait_for` isn't `Send`, so it must not be held across an await.
+        _ = async { shutdown.wait_for(|&shutdown| shutdown).await.is_ok() } => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        },
+        _ = conn_idle.wait_idle(shared.http.idle_timeout) => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        },
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error serving connection: {:?}", err);
+    }
 }
 
 #[tokio::main]
@@ -70,42 +417,154 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let config = load_config();
     println!("Using config: {:?}", config);
 
-    let listener = get_listener_from_systemd()?;
+    let mut connection = None;
+    let listeners = if let Some(addr) = &config.listen {
+        let listener = match get_inherited_listener(addr)? {
+            Some(listener) => {
+                println!("Using socket for {} from previous process", addr);
+                listener
+            },
+            None => {
+                let listener = Listener::bind(addr).await?;
+                println!("Listening on {}", addr);
+                listener
+            },
+        };
+        store_listener(&listener);
+        vec![(LISTEN_SOCKET_NAME.to_string(), listener, config.profile.clone())]
+    } else if !config.sockets.is_empty() {
+        get_named_listeners_from_systemd(&config.sockets)?
+    } else {
+        match get_socket_from_systemd()? {
+            Activated::Listener(listener) => vec![(LISTEN_SOCKET_NAME.to_string(), listener, config.profile.clone())],
+            Activated::Connection(stream) => {
+                println!("Serving a single connection from systemd");
+                connection = Some(stream);
+                vec![]
+            },
+        }
+    };
 
-    // We start a loop to continuously accept incoming connections
-    loop {
-        let (stream, _) = if let Some(max_idle_time) = config.max_idle_time {
-            let accept_future = listener.accept();
-            let timeout_future = tokio::time::timeout(max_idle_time, accept_future);
-            match timeout_future.await {
-                Ok(accept_result) => accept_result,
-                Err(_) => {
-                    eprintln!("Timed out waiting for new connection. Exiting.");
-                    process::exit(0);
-                },
-            }
-        } else {
-            listener.accept().await
-        }.expect("accepting connection");
+    let shared = Arc::new(Shared {
+        idle: Arc::new(IdleTracker::new()),
+        status: Arc::new(Status::new()),
+        watchdog: Arc::new(Watchdog::new()),
+        tls: config.tls.as_ref().map(setup_tls),
+        http: config.http.clone(),
+        deliveries: config.replay_protection.as_ref().map(open_delivery_store),
+        shutdown: watch::Sender::new(false),
+    });
 
-        let io = TokioIo::new(stream);
-        let cfg = config.clone();
+    let mut handoff = Handoff::new();
+    // The new process needs the secrets which have been removed from the environment.
+    for (var, value) in config.secret_variables() {
+        handoff.set_env(&var, value);
+    }
+    let mut accept_loops = JoinSet::new();
+    for (name, listener, profile) in listeners {
+        handoff.add(&name, listener.as_fd())?;
+        accept_loops.spawn(accept_loop(listener, Arc::new(profile), shared.clone()));
+    }
 
-        // Spawn a tokio task to serve multiple connections concurrently.
-        tokio::task::spawn(async move {
-            let service = service_fn(|req: Request<hyper::body::Incoming>| {
-                service::router(req, &cfg)
-            });
+    // With `Accept=yes`, we are done once the connection is closed and the commands it triggered
+    // have finished. The connection runs in its own task, so it can drain like any other on SIGTERM.
+    let connection_done = async {
+        match connection {
+            Some(stream) => {
+                let busy = shared.idle.busy();
+                tokio::spawn(handle_connection(stream, Arc::new(config.profile.clone()), shared.clone(), busy));
+                shared.idle.wait_done().await
+            },
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(connection_done);
 
-            let conn = http1::Builder::new()
-                // On OSX, disabling keep alive prevents serve_connection from
-                // blocking and later returning an `Err` derived from `ENOTCONN`.
-                .keep_alive(false)
-                .serve_connection(io, service);
+    let idle_timeout = async {
+        match config.max_idle_time {
+            Some(max_idle_time) => shared.idle.wait_idle(max_idle_time).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(idle_timeout);
 
-            if let Err(err) = conn.await {
-                eprintln!("Error serving connection: {:?}", err);
-            }
-        });
+    let watchdog = match systemd_socket::watchdog_enabled(true) {
+        Ok(Some(timeout)) => {
+            println!("Watchdog enabled, timeout {}", humantime::format_duration(timeout));
+            handoff.set_watchdog(timeout);
+            let watchdog = shared.watchdog.clone();
+            Some(tokio::spawn(async move { watchdog.run(timeout).await }))
+        },
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Ignoring invalid watchdog settings: {}", e);
+            None
+        },
+    };
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut upgrade = signal(SignalKind::user_defined2())?;
+    shared.status.ready();
+
+    loop {
+        tokio::select! {
+            _ = &mut idle_timeout => {
+                eprintln!("No connections or commands for {}.", humantime::format_duration(config.max_idle_time.unwrap()));
+                shared.status.stopping("Idle, draining before exit");
+            },
+            _ = &mut connection_done => {
+                eprintln!("Connection closed and all commands finished.");
+                shared.status.stopping("Connection closed, exiting");
+            },
+            _ = terminate.recv() => {
+                eprintln!("Received SIGTERM.");
+                shared.status.stopping("Stopping, draining before exit");
+            },
+            _ = upgrade.recv() => {
+                eprintln!("Received SIGUSR2.");
+                if handoff.is_empty() {
+                    eprintln!("No listening sockets to hand over, ignoring.");
+                    continue;
+                }
+                shared.status.prepare_hand_over();
+                match handoff.spawn() {
+                    Ok(child) => {
+                        eprintln!("Started new process {}, handing over.", child.id());
+                        // The new process takes over reporting to systemd, including the watchdog.
+                        shared.status.hand_over(child.id());
+                        if let Some(watchdog) = &watchdog {
+                            watchdog.abort();
+                        }
+                    },
+                    Err(e) => {
+                        eprintln!("Failed to start new process, continuing: {}", e);
+                        shared.status.cancel_hand_over();
+                        continue;
+                    },
+                }
+            },
+            Some(result) = accept_loops.join_next() => {
+                result??;
+                unreachable!("accept loops only return on error");
+            },
+        }
+        break;
+    }
+
+    // Stop accepting new connections. With socket activation, new connections queue up in the
+    // socket held by systemd, which will start us again once we have exited.
+    accept_loops.shutdown().await;
+    shared.shutdown.send_replace(true);
+
+    let in_flight = shared.idle.busy_count();
+    if in_flight > 0 {
+        eprintln!("Waiting up to {} for {} connection(s) and command(s) to finish.",
+                  humantime::format_duration(config.shutdown_grace_period), in_flight);
+    }
+    match tokio::time::timeout(config.shutdown_grace_period, shared.idle.wait_done()).await {
+        Ok(()) => eprintln!("Exiting."),
+        Err(_) => eprintln!("Grace period expired with {} connection(s) and command(s) still running. Exiting anyway.",
+                            shared.idle.busy_count()),
     }
+    process::exit(0);
 }
@@ -0,0 +1,866 @@
+//! The services which send us webhooks. They all deliver events as a POST request with a JSON
+//! payload, but differ in how they name the event and how they prove that a request came from
+//! them.
+
+use crate::config::{self, Secret};
+use crate::public_key::PublicKey;
+use crate::secret::Redacted;
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue};
+
+use base64::Engine;
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use subtle::ConstantTimeEq;
+use std::borrow::Cow;
+use std::time::{Duration, SystemTime};
+
+/// The service sending webhooks to a profile, which determines how requests are verified.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// GitHub signs the payload with the secret, using HMAC-SHA256.
+    #[default]
+    Github,
+
+    /// GitLab sends the secret itself in the `X-Gitlab-Token` header.
+    Gitlab,
+
+    /// Forgejo (and Gitea, which it is a fork of) sign the payload with HMAC-SHA256 like GitHub,
+    /// but send the signature in different headers and without the `sha256=` prefix.
+    #[serde(alias = "gitea")]
+    Forgejo,
+
+    /// Services following the [Standard Webhooks](https://www.standardwebhooks.com/) spec, which
+    /// sign the payload along with the delivery ID and a timestamp.
+    #[serde(rename = "standard_webhooks")]
+    StandardWebhooks,
+
+    /// Any other service which signs the payload with HMAC, e.g.
+    /// `{"hmac": {"header": "X-Signature", "algorithm": "sha512", "encoding": "base64"}}`.
+    Hmac(HmacScheme),
+
+    /// Services which sign the payload with a private key, so only the public key has to be
+    /// configured, e.g. `{"public_key": {"key": "...", "header": "X-Signature-Ed25519"}}`.
+    #[serde(rename = "public_key")]
+    PublicKey(PublicKeyScheme),
+}
+
+/// How a request was verified.
+#[derive(Debug)]
+pub enum Verified<'a> {
+    /// With one of the secrets shared with the sender.
+    Secret(&'a Secret),
+
+    /// With the sender's public key.
+    PublicKey,
+}
+
+/// How a service signs its requests with HMAC, and where it puts the event type.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct HmacScheme {
+    /// The header carrying the signature.
+    pub header: Cow<'static, str>,
+
+    /// Text in front of the signature in the header, e.g. `sha256=`.
+    #[serde(default)]
+    pub prefix: Cow<'static, str>,
+
+    #[serde(default)]
+    pub algorithm: Algorithm,
+
+    #[serde(default)]
+    pub encoding: Encoding,
+
+    /// The header carrying the type of event.
+    #[serde(default)]
+    pub event_header: Option<Cow<'static, str>>,
+
+    /// A JSON pointer to the type of event in the payload, e.g. `/event/type`. Only used if there
+    /// is no [`event_header`](HmacScheme::event_header). Without either, every request is the event
+    /// [`DEFAULT_EVENT`].
+    #[serde(default)]
+    pub event_pointer: Option<String>,
+
+    /// The header carrying the unique ID of a delivery, if any.
+    #[serde(default)]
+    pub delivery_header: Option<Cow<'static, str>>,
+}
+
+/// The event of requests from services which don't say what kind of event they are sending.
+pub const DEFAULT_EVENT: &str = "webhook";
+
+/// How a service signs its requests with a private key, and where it puts the event type.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct PublicKeyScheme {
+    /// The public key of the sender.
+    pub key: PublicKey,
+
+    /// The header carrying the signature.
+    pub header: String,
+
+    /// Text in front of the signature in the header.
+    #[serde(default)]
+    pub prefix: String,
+
+    #[serde(default)]
+    pub encoding: Encoding,
+
+    /// The header carrying the time the request was signed at, in seconds since the Unix epoch.
+    /// If set, the signature covers the timestamp followed by the payload, and requests outside
+    /// of [`timestamp_tolerance`](crate::config::Profile::timestamp_tolerance) are rejected.
+    #[serde(default)]
+    pub timestamp_header: Option<String>,
+
+    /// Like [`HmacScheme::event_header`].
+    #[serde(default)]
+    pub event_header: Option<String>,
+
+    /// Like [`HmacScheme::event_pointer`].
+    #[serde(default)]
+    pub event_pointer: Option<String>,
+
+    /// The header carrying the unique ID of a delivery, if any.
+    #[serde(default)]
+    pub delivery_header: Option<String>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+/// How the signature is written in the header.
+#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+/// To verify the authenticity of the event, GitHub attaches a signature of the payload to every
+/// request. The header value will look something like this:
+///
+///     x-hub-signature-256: sha256=6803d2a3e495fc4bd286d428ea4b794476a1ff1b72bbea4dfafd2477d5d89188
+const GITHUB: HmacScheme = HmacScheme {
+    header: Cow::Borrowed("x-hub-signature-256"),
+    prefix: Cow::Borrowed("sha256="),
+    algorithm: Algorithm::Sha256,
+    encoding: Encoding::Hex,
+    event_header: Some(Cow::Borrowed("X-GitHub-Event")),
+    event_pointer: None,
+    delivery_header: Some(Cow::Borrowed("X-GitHub-Delivery")),
+};
+
+/// Older versions of GitHub Enterprise Server only send an SHA-1 signature:
+///
+///     x-hub-signature: sha1=007eefb01bdb525f2b04030e1d7e6a132a07f16b
+const GITHUB_SHA1: HmacScheme = HmacScheme {
+    header: Cow::Borrowed("x-hub-signature"),
+    prefix: Cow::Borrowed("sha1="),
+    algorithm: Algorithm::Sha1,
+    ..GITHUB
+};
+
+/// Forgejo sends a bare signature:
+///
+///     x-forgejo-signature: b4f5271e4048257f3d7a9de6b686b728754c7561657ef6bd49fe4170a98a548e
+const FORGEJO: HmacScheme = HmacScheme {
+    header: Cow::Borrowed("x-forgejo-signature"),
+    prefix: Cow::Borrowed(""),
+    algorithm: Algorithm::Sha256,
+    encoding: Encoding::Hex,
+    event_header: Some(Cow::Borrowed("X-Gitea-Event")),
+    event_pointer: None,
+    delivery_header: Some(Cow::Borrowed("X-Gitea-Delivery")),
+};
+
+/// Forgejo sends the same signature under this name too, Gitea only under this one.
+const GITEA: HmacScheme = HmacScheme {
+    header: Cow::Borrowed("x-gitea-signature"),
+    ..FORGEJO
+};
+
+impl Provider {
+    /// The header carrying the unique ID of a delivery, if the provider sends one.
+    pub fn delivery_header(&self) -> Option<&str> {
+        match self {
+            Provider::Github => GITHUB.delivery_header.as_deref(),
+            Provider::Gitlab => Some("X-Gitlab-Event-UUID"),
+            Provider::Forgejo => FORGEJO.delivery_header.as_deref(),
+            Provider::StandardWebhooks => Some("webhook-id"),
+            Provider::Hmac(scheme) => scheme.delivery_header.as_deref(),
+            Provider::PublicKey(scheme) => scheme.delivery_header.as_deref(),
+        }
+    }
+
+    /// Extracts the type of event from the request, named the way
+    /// [`Command::event`](crate::config::Command::event) expects it. If it is missing or invalid,
+    /// the error says so.
+    pub fn event(&self, headers: &HeaderMap<HeaderValue>, body: &[u8]) -> Result<String, String> {
+        match self {
+            Provider::Github => GITHUB.event(headers, body),
+            Provider::Gitlab => header_event(headers, "X-Gitlab-Event").map(gitlab_event_name),
+            Provider::Forgejo => FORGEJO.event(headers, body),
+            Provider::StandardWebhooks => payload_event(body, "/type"),
+            Provider::Hmac(scheme) => scheme.event(headers, body),
+            Provider::PublicKey(scheme) => scheme.event(headers, body),
+        }
+    }
+
+    /// Checks that a request was sent by someone who knows one of the `secrets` (or the private key,
+    /// for [`Provider::PublicKey`]), returning what it was verified with. Only secrets active at
+    /// `now` are considered. Requests with a signed timestamp further than `tolerance` from `now`
+    /// are rejected.
+    pub fn verify<'a>(
+        &self,
+        secrets: &'a [Secret],
+        allow_sha1: bool,
+        tolerance: Duration,
+        now: SystemTime,
+        headers: &HeaderMap<HeaderValue>,
+        body: &Bytes,
+    ) -> Option<Verified<'a>> {
+        let secret = match self {
+            Provider::Github => validate_request(secrets, allow_sha1, now, headers, body),
+            Provider::Gitlab => validate_token(secrets, now, headers, "x-gitlab-token"),
+            Provider::Forgejo if headers.contains_key(&*FORGEJO.header) => FORGEJO.verify(secrets, now, headers, body),
+            Provider::Forgejo => GITEA.verify(secrets, now, headers, body),
+            Provider::StandardWebhooks => validate_standard_webhook(secrets, tolerance, now, headers, body),
+            Provider::Hmac(scheme) => scheme.verify(secrets, now, headers, body),
+            Provider::PublicKey(scheme) => {
+                return scheme.verify(tolerance, now, headers, body).then_some(Verified::PublicKey);
+            },
+        };
+        secret.map(Verified::Secret)
+    }
+
+    /// Whether requests are verified with secrets, which the profile must then have.
+    pub fn uses_secrets(&self) -> bool {
+        !matches!(self, Provider::PublicKey(_))
+    }
+}
+
+impl HmacScheme {
+    fn event(&self, headers: &HeaderMap<HeaderValue>, body: &[u8]) -> Result<String, String> {
+        event(self.event_header.as_deref(), self.event_pointer.as_deref(), headers, body)
+    }
+
+    /// Finds the active secret which the request was signed with.
+    fn verify<'a>(
+        &self,
+        secrets: &'a [Secret],
+        now: SystemTime,
+        headers: &HeaderMap<HeaderValue>,
+        body: &Bytes,
+    ) -> Option<&'a Secret> {
+        let signature = header_signature(headers, &self.header, &self.prefix, self.encoding)?;
+        match self.algorithm {
+            Algorithm::Sha1 => find_secret::<Hmac<Sha1>>(secrets, now, body, &signature),
+            Algorithm::Sha256 => find_secret::<Hmac<Sha256>>(secrets, now, body, &signature),
+            Algorithm::Sha512 => find_secret::<Hmac<Sha512>>(secrets, now, body, &signature),
+        }
+    }
+}
+
+impl PublicKeyScheme {
+    fn event(&self, headers: &HeaderMap<HeaderValue>, body: &[u8]) -> Result<String, String> {
+        event(self.event_header.as_deref(), self.event_pointer.as_deref(), headers, body)
+    }
+
+    /// Checks that the request was signed with the private key belonging to our public key.
+    fn verify(&self, tolerance: Duration, now: SystemTime, headers: &HeaderMap<HeaderValue>, body: &Bytes) -> bool {
+        let Some(signature) = header_signature(headers, &self.header, &self.prefix, self.encoding) else {
+            return false;
+        };
+        match &self.timestamp_header {
+            Some(header) => {
+                let Some(timestamp) = headers.get(header).and_then(|hv| hv.to_str().ok()) else {
+                    return false;
+                };
+                within_tolerance(timestamp, tolerance, now)
+                    && self.key.verify(&[timestamp.as_bytes(), body].concat(), &signature)
+            },
+            None => self.key.verify(body, &signature),
+        }
+    }
+}
+
+/// Extracts the signature from the header `name`, whose value must consist of `prefix` followed
+/// by the signature in the given encoding.
+fn header_signature(headers: &HeaderMap<HeaderValue>, name: &str, prefix: &str, encoding: Encoding) -> Option<Vec<u8>> {
+    let signature = headers
+        .get(name)
+        .and_then(|hv| hv.to_str().ok())         // HeaderValue => &str
+        .and_then(|s| s.strip_prefix(prefix))?   // sha256=2843i4aklds... => 2843i4aklds...
+        .trim();
+    match encoding {
+        Encoding::Hex => decode_hex(signature),
+        Encoding::Base64 => base64::engine::general_purpose::STANDARD.decode(signature).ok(),
+    }
+}
+
+/// Finds the type of event in the header `event_header` if given, otherwise in the payload at
+/// `event_pointer`. Without either, it is [`DEFAULT_EVENT`].
+fn event(
+    event_header: Option<&str>,
+    event_pointer: Option<&str>,
+    headers: &HeaderMap<HeaderValue>,
+    body: &[u8],
+) -> Result<String, String> {
+    match (event_header, event_pointer) {
+        (Some(header), _) => header_event(headers, header).map(str::to_string),
+        (None, Some(pointer)) => payload_event(body, pointer),
+        (None, None) => Ok(DEFAULT_EVENT.to_string()),
+    }
+}
+
+/// Checks that `timestamp`, in seconds since the Unix epoch, is no further than `tolerance` from
+/// `now`.
+fn within_tolerance(timestamp: &str, tolerance: Duration, now: SystemTime) -> bool {
+    let Ok(seconds) = timestamp.parse() else {
+        return false;
+    };
+    // Timestamps too large for `SystemTime` are certainly not within the tolerance.
+    let Some(signed_at) = SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds)) else {
+        return false;
+    };
+    let offset = now.duration_since(signed_at).unwrap_or_else(|e| e.duration());
+    if offset > tolerance {
+        eprintln!("Rejecting request signed {} {} now, which is outside the tolerance of {}",
+                  config::format_secs(offset), if signed_at < now { "before" } else { "after" },
+                  config::format_secs(tolerance));
+        return false;
+    }
+    true
+}
+
+/// Returns the value of the header `name`, or an error saying why there is none.
+fn header_event<'h>(headers: &'h HeaderMap<HeaderValue>, name: &str) -> Result<&'h str, String> {
+    match headers.get(name).map(HeaderValue::to_str) {
+        Some(Ok(event)) => Ok(event),
+        Some(Err(_)) => Err(format!("Invalid ASCII in header: {}", name)),
+        None => Err(format!("Missing header: {}", name)),
+    }
+}
+
+/// Returns the string at the JSON pointer `pointer` in the payload, or an error saying why there is
+/// none.
+fn payload_event(body: &[u8], pointer: &str) -> Result<String, String> {
+    let payload = serde_json::from_slice::<serde_json::Value>(body)
+        .map_err(|_| "Payload is not valid JSON".to_string())?;
+    match payload.pointer(pointer) {
+        Some(serde_json::Value::String(event)) => Ok(event.clone()),
+        _ => Err(format!("Missing event in payload: {}", pointer)),
+    }
+}
+
+/// Turns GitLab's event names into the names used for [`Provider::Github`] and in the payload's
+/// `object_kind`, e.g. `Push Hook` into `push` and `Merge Request Hook` into `merge_request`.
+fn gitlab_event_name(event: &str) -> String {
+    event.strip_suffix(" Hook")
+        .unwrap_or(event)
+        .to_lowercase()
+        .replace(' ', "_")
+}
+
+/// Decodes a string slice into a string of bytes, or returns `None` if it isn't valid hex.
+///
+/// Implementation adapted from [this stackoverflow post](https://stackoverflow.com/a/52992629).
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Validates the signature that GitHub attaches to events, returning the secret it was made with.
+/// Only secrets active at `now` are considered. With `allow_sha1`, requests which only carry the
+/// legacy SHA-1 signature are checked against that instead.
+fn validate_request<'a>(
+    secrets: &'a [Secret],
+    allow_sha1: bool,
+    now: SystemTime,
+    headers: &HeaderMap<HeaderValue>,
+    body: &Bytes,
+) -> Option<&'a Secret> {
+    if headers.contains_key(&*GITHUB.header) {
+        return GITHUB.verify(secrets, now, headers, body);
+    }
+    if allow_sha1 && headers.contains_key(&*GITHUB_SHA1.header) {
+        eprintln!("warning: request is only signed with SHA-1, which is deprecated. Please upgrade the sender to use SHA-256.");
+        return GITHUB_SHA1.verify(secrets, now, headers, body);
+    }
+    None
+}
+
+/// Finds the active secret which `signature` was computed with, using the HMAC `M`.
+fn find_secret<'a, M: Mac + KeyInit>(
+    secrets: &'a [Secret],
+    now: SystemTime,
+    body: &Bytes,
+    signature: &[u8],
+) -> Option<&'a Secret> {
+    // Now we independantly calculate a signature of the payload we just read, using each secret. If
+    // the sender computed the signature with the same secret, we should be all good.
+    secrets.iter()
+        .filter(|secret| secret.is_active(now))
+        .find(|secret| {
+            let mut mac = <M as Mac>::new_from_slice(secret.value().expose()).unwrap();
+            mac.update(body);
+            mac.verify_slice(signature).is_ok()
+        })
+}
+
+/// Validates a request which carries the secret itself in the header `name`, returning the secret.
+fn validate_token<'a>(
+    secrets: &'a [Secret],
+    now: SystemTime,
+    headers: &HeaderMap<HeaderValue>,
+    name: &str,
+) -> Option<&'a Secret> {
+    let token = headers.get(name)?.as_bytes();
+
+    // The comparison takes the same time no matter how much of the token is right, so it can't be
+    // guessed byte by byte. Only the length of the secret could be learned this way.
+    secrets.iter()
+        .filter(|secret| secret.is_active(now))
+        .find(|secret| secret.value().expose().ct_eq(token).into())
+}
+
+/// Validates a request signed according to the [Standard Webhooks
+/// spec](https://github.com/standard-webhooks/standard-webhooks/blob/main/spec/standard-webhooks.md),
+/// returning the secret it was signed with. The headers look like this:
+///
+///     webhook-id: msg_p5jXN8AQM9LWM0D4loKWxJek
+///     webhook-timestamp: 1614265330
+///     webhook-signature: v1,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE=
+///
+/// The signature is an HMAC-SHA256 of the ID, the timestamp and the payload, separated by dots.
+/// There may be several signatures separated by spaces, e.g. while the sender rotates its secret,
+/// and any of them is good enough. Requests whose timestamp is further than `tolerance` from `now`
+/// are rejected, so they can't be replayed later.
+fn validate_standard_webhook<'a>(
+    secrets: &'a [Secret],
+    tolerance: Duration,
+    now: SystemTime,
+    headers: &HeaderMap<HeaderValue>,
+    body: &Bytes,
+) -> Option<&'a Secret> {
+    let id = headers.get("webhook-id")?.as_bytes();
+    let timestamp = headers.get("webhook-timestamp")?.to_str().ok()?;
+    let signatures = headers.get("webhook-signature")?.to_str().ok()?;
+
+    if !within_tolerance(timestamp, tolerance, now) {
+        return None;
+    }
+
+    // Signatures of other versions (or schemes) can't be checked, so they are skipped.
+    let signatures = signatures.split(' ')
+        .filter_map(|signature| signature.strip_prefix("v1,"))
+        .filter_map(|signature| base64::engine::general_purpose::STANDARD.decode(signature).ok())
+        .collect::<Vec<_>>();
+
+    secrets.iter()
+        .filter(|secret| secret.is_active(now))
+        .find(|secret| {
+            let Some(key) = standard_webhooks_key(&secret.value()) else {
+                eprintln!("warning: secret from {} is not valid base64, as Standard Webhooks require", secret.source);
+                return false;
+            };
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key.expose()).unwrap();
+            mac.update(id);
+            mac.update(b".");
+            mac.update(timestamp.as_bytes());
+            mac.update(b".");
+            mac.update(body);
+            let expected = mac.finalize().into_bytes();
+            signatures.iter().any(|signature| bool::from(expected.as_slice().ct_eq(signature)))
+        })
+}
+
+/// Decodes a Standard Webhooks secret, which is base64 with an optional `whsec_` prefix.
+fn standard_webhooks_key(secret: &Redacted) -> Option<Redacted> {
+    let secret = secret.expose();
+    let encoded = secret.strip_prefix(b"whsec_").unwrap_or(secret);
+    base64::engine::general_purpose::STANDARD.decode(encoded).ok().map(Redacted::from_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_hex, gitlab_event_name, Algorithm, Encoding, HmacScheme, Provider, Verified};
+    use crate::config::SecretSource;
+    use crate::test_util::{assert_matches, sample_request, secret, TOLERANCE};
+    use hyper::body::Bytes;
+    use hyper::header::{HeaderMap, HeaderValue};
+    use std::borrow::Cow;
+    use std::time::{Duration, SystemTime};
+
+    const SAMPLE_SIGNATURE_SHA1: &str = "sha1=007eefb01bdb525f2b04030e1d7e6a132a07f16b";
+    const SAMPLE_SIGNATURE_FORGEJO: &str = "b4f5271e4048257f3d7a9de6b686b728754c7561657ef6bd49fe4170a98a548e";
+    const SAMPLE_SIGNATURE_SHA512: &str = "v1=Mtu/9AKQDtG6Sn6IsFeJfT+mprAH18lj+pA2nIAXBmDavy44PjxIidowKZUosYn0l1pp2o4cJMoP0gxZEqfhfA==";
+
+    fn gitlab_request(event: &'static str, payload: &str) -> (HeaderMap<HeaderValue>, Bytes) {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Gitlab-Event", HeaderValue::from_static(event));
+        headers.insert("X-Gitlab-Token", HeaderValue::from_static("mysecret"));
+        let body = std::fs::read(format!("examples/{}", payload)).unwrap();
+        (headers, Bytes::from(body))
+    }
+
+    fn forgejo_request() -> (HeaderMap<HeaderValue>, Bytes) {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Gitea-Event", HeaderValue::from_static("push"));
+        headers.insert("X-Forgejo-Signature", HeaderValue::from_static(SAMPLE_SIGNATURE_FORGEJO));
+        headers.insert("X-Gitea-Signature", HeaderValue::from_static(SAMPLE_SIGNATURE_FORGEJO));
+        let body = std::fs::read("examples/sample_forgejo_push_payload.json").unwrap();
+        (headers, Bytes::from(body))
+    }
+
+    #[test]
+    fn any_active_secret_matches() {
+        let (headers, body) = sample_request();
+        let now = SystemTime::now();
+        let secrets = [
+            secret("new", "newsecret", None),
+            secret("old", "mysecret", Some(now + Duration::from_secs(60))),
+        ];
+        let Some(Verified::Secret(matched)) = Provider::Github.verify(&secrets, false, TOLERANCE, now, &headers, &body) else {
+            panic!("signature is valid");
+        };
+        assert_eq!(matched.source, SecretSource::Env("old".to_string()));
+
+        // Once the old secret has expired, the request is rejected.
+        assert!(Provider::Github.verify(&secrets, false, TOLERANCE, now + Duration::from_secs(60), &headers, &body).is_none());
+    }
+
+    #[test]
+    fn missing_or_invalid_signature() {
+        let (mut headers, body) = sample_request();
+        let secrets = [secret("secret", "mysecret", None)];
+        let now = SystemTime::now();
+        assert!(Provider::Github.verify(&secrets, false, TOLERANCE, now, &headers, &body[1..].to_vec().into()).is_none());
+
+        headers.insert("X-Hub-Signature-256", HeaderValue::from_static("sha256=zz"));
+        assert!(Provider::Github.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+
+        headers.remove("X-Hub-Signature-256");
+        assert!(Provider::Github.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+    }
+
+    #[test]
+    fn sha1_only_if_allowed() {
+        let (mut headers, body) = sample_request();
+        let secrets = [secret("secret", "mysecret", None)];
+        let now = SystemTime::now();
+        headers.remove("X-Hub-Signature-256");
+        headers.insert("X-Hub-Signature", HeaderValue::from_static(SAMPLE_SIGNATURE_SHA1));
+        assert!(Provider::Github.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+        assert!(Provider::Github.verify(&secrets, true, TOLERANCE, now, &headers, &body).is_some());
+
+        // When there's an SHA-256 signature, it has to be valid.
+        headers.insert("X-Hub-Signature-256", HeaderValue::from_static("sha256=00"));
+        assert!(Provider::Github.verify(&secrets, true, TOLERANCE, now, &headers, &body).is_none());
+    }
+
+    #[test]
+    fn gitlab_events() {
+        assert_eq!(gitlab_event_name("Push Hook"), "push");
+        assert_eq!(gitlab_event_name("Tag Push Hook"), "tag_push");
+        assert_eq!(gitlab_event_name("Merge Request Hook"), "merge_request");
+        assert_eq!(gitlab_event_name("Pipeline Hook"), "pipeline");
+
+        let (headers, body) = gitlab_request("Push Hook", "sample_gitlab_push_payload.json");
+        assert_eq!(Provider::Gitlab.event(&headers, &body), Ok("push".to_string()));
+        let (headers, body) = gitlab_request("Merge Request Hook", "sample_gitlab_merge_request_payload.json");
+        assert_eq!(Provider::Gitlab.event(&headers, &body), Ok("merge_request".to_string()));
+        // A GitLab request doesn't look like a GitHub one.
+        assert_eq!(Provider::Github.event(&headers, &body), Err("Missing header: X-GitHub-Event".to_string()));
+    }
+
+    #[test]
+    fn gitlab_token() {
+        let (mut headers, body) = gitlab_request("Push Hook", "sample_gitlab_push_payload.json");
+        let now = SystemTime::now();
+        let secrets = [secret("new", "newsecret", None), secret("old", "mysecret", None)];
+        let Some(Verified::Secret(matched)) = Provider::Gitlab.verify(&secrets, false, TOLERANCE, now, &headers, &body) else {
+            panic!("token is valid");
+        };
+        assert_eq!(matched.source, SecretSource::Env("old".to_string()));
+
+        // GitHub's signature means nothing to GitLab, and vice versa.
+        let (github_headers, github_body) = sample_request();
+        assert!(Provider::Gitlab.verify(&secrets, false, TOLERANCE, now, &github_headers, &github_body).is_none());
+        assert!(Provider::Github.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+
+        for token in ["mysecreT", "mysecre", "mysecrets", ""] {
+            headers.insert("X-Gitlab-Token", HeaderValue::from_static(token));
+            assert!(Provider::Gitlab.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none(), "{}", token);
+        }
+        headers.remove("X-Gitlab-Token");
+        assert!(Provider::Gitlab.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+    }
+
+    #[test]
+    fn forgejo_signature() {
+        let (mut headers, body) = forgejo_request();
+        let now = SystemTime::now();
+        let secrets = [secret("secret", "mysecret", None)];
+        assert_eq!(Provider::Forgejo.event(&headers, &body), Ok("push".to_string()));
+        assert!(Provider::Forgejo.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_some());
+        assert!(Provider::Forgejo.verify(&secrets, false, TOLERANCE, now, &headers, &body[1..].to_vec().into()).is_none());
+
+        // Gitea only sends one of the headers.
+        headers.remove("X-Forgejo-Signature");
+        assert!(Provider::Forgejo.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_some());
+
+        // The signature is bare hex, so GitHub's prefix makes it invalid.
+        let prefixed = format!("sha256={}", SAMPLE_SIGNATURE_FORGEJO);
+        headers.insert("X-Gitea-Signature", HeaderValue::from_str(&prefixed).unwrap());
+        assert!(Provider::Forgejo.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+
+        // A GitHub request doesn't pass as a Forgejo one.
+        let (github_headers, github_body) = sample_request();
+        assert!(Provider::Forgejo.verify(&secrets, false, TOLERANCE, now, &github_headers, &github_body).is_none());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00ff7f"), Some(vec![0x00, 0xff, 0x7f]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("éa"), None);
+    }
+
+    #[test]
+    fn deserialize_hmac_scheme() {
+        let provider = serde_json::from_str::<Provider>(r#"{"hmac": {"header": "X-Signature"}}"#).unwrap();
+        assert_eq!(provider, Provider::Hmac(HmacScheme {
+            header: Cow::Borrowed("X-Signature"),
+            prefix: Cow::Borrowed(""),
+            algorithm: Algorithm::Sha256,
+            encoding: Encoding::Hex,
+            event_header: None,
+            event_pointer: None,
+            delivery_header: None,
+        }));
+
+        let provider = serde_json::from_str::<Provider>(r#"{"hmac": {
+            "header": "X-Signature",
+            "prefix": "v1=",
+            "algorithm": "sha512",
+            "encoding": "base64",
+            "event_pointer": "/event/type"
+        }}"#).unwrap();
+        let Provider::Hmac(scheme) = provider else { panic!("not an HMAC scheme") };
+        assert_eq!(scheme.algorithm, Algorithm::Sha512);
+        assert_eq!(scheme.encoding, Encoding::Base64);
+        assert_eq!(scheme.event_pointer.as_deref(), Some("/event/type"));
+
+        assert!(serde_json::from_str::<Provider>(r#"{"hmac": {"header": "X-Signature", "algorithm": "md5"}}"#).is_err());
+    }
+
+    #[test]
+    fn hmac_scheme_signature() {
+        let (_, body) = sample_request();
+        let now = SystemTime::now();
+        let secrets = [secret("secret", "mysecret", None)];
+        let provider = Provider::Hmac(HmacScheme {
+            header: Cow::Borrowed("X-Signature"),
+            prefix: Cow::Borrowed("v1="),
+            algorithm: Algorithm::Sha512,
+            encoding: Encoding::Base64,
+            event_header: None,
+            event_pointer: None,
+            delivery_header: None,
+        });
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature", HeaderValue::from_static(SAMPLE_SIGNATURE_SHA512));
+        assert!(provider.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_some());
+        assert!(provider.verify(&secrets, false, TOLERANCE, now, &headers, &body[1..].to_vec().into()).is_none());
+
+        // The GitHub preset doesn't accept anything but its own header.
+        assert!(Provider::Github.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+        let (github_headers, _) = sample_request();
+        assert!(provider.verify(&secrets, false, TOLERANCE, now, &github_headers, &body).is_none());
+
+        headers.insert("X-Signature", HeaderValue::from_static("v1=not base64!"));
+        assert!(provider.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+    }
+
+    #[test]
+    fn hmac_scheme_event() {
+        let mut scheme = HmacScheme {
+            header: Cow::Borrowed("X-Signature"),
+            prefix: Cow::Borrowed(""),
+            algorithm: Algorithm::Sha256,
+            encoding: Encoding::Hex,
+            event_header: None,
+            event_pointer: None,
+            delivery_header: None,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Event", HeaderValue::from_static("build.finished"));
+        let body = br#"{"event": {"type": "deploy.requested"}, "id": 1}"#;
+        assert_eq!(scheme.event(&headers, body), Ok("webhook".to_string()));
+
+        scheme.event_pointer = Some("/event/type".to_string());
+        assert_eq!(scheme.event(&headers, body), Ok("deploy.requested".to_string()));
+        assert!(scheme.event(&headers, br#"{"event": {}}"#).is_err());
+        assert!(scheme.event(&headers, b"not json").is_err());
+
+        scheme.event_pointer = Some("/id".to_string());
+        assert_eq!(scheme.event(&headers, body), Err("Missing event in payload: /id".to_string()));
+
+        // The header takes precedence.
+        scheme.event_header = Some(Cow::Borrowed("X-Event"));
+        assert_eq!(scheme.event(&headers, body), Ok("build.finished".to_string()));
+    }
+
+    // These are the test vectors used by the reference implementations of Standard Webhooks.
+    const STANDARD_WEBHOOKS_SECRET: &str = "whsec_MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw";
+    const STANDARD_WEBHOOKS_ID: &str = "msg_p5jXN8AQM9LWM0D4loKWxJek";
+    const STANDARD_WEBHOOKS_TIMESTAMP: u64 = 1614265330;
+    const STANDARD_WEBHOOKS_PAYLOAD: &str = r#"{"test": 2432232314}"#;
+    const STANDARD_WEBHOOKS_SIGNATURE: &str = "v1,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE=";
+
+    fn standard_webhook(signature: &str) -> (HeaderMap<HeaderValue>, Bytes) {
+        let mut headers = HeaderMap::new();
+        headers.insert("webhook-id", HeaderValue::from_static(STANDARD_WEBHOOKS_ID));
+        headers.insert("webhook-timestamp", HeaderValue::from(STANDARD_WEBHOOKS_TIMESTAMP));
+        headers.insert("webhook-signature", HeaderValue::from_str(signature).unwrap());
+        (headers, Bytes::from_static(STANDARD_WEBHOOKS_PAYLOAD.as_bytes()))
+    }
+
+    #[test]
+    fn standard_webhooks_signature() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(STANDARD_WEBHOOKS_TIMESTAMP);
+        let secrets = [secret("secret", STANDARD_WEBHOOKS_SECRET, None)];
+        let verify = |(headers, body): (HeaderMap<HeaderValue>, Bytes)| {
+            Provider::StandardWebhooks.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_some()
+        };
+
+        assert!(verify(standard_webhook(STANDARD_WEBHOOKS_SIGNATURE)));
+        // Any of several signatures may match, but only those of version 1 count.
+        assert!(verify(standard_webhook(&format!("v1,Ceo5qEr07ixe2NLpvHk3FH9bwy/WavXrAFQ/9tdO6mc= {}", STANDARD_WEBHOOKS_SIGNATURE))));
+        assert!(verify(standard_webhook(&format!("v1,bm9wZQ== {} v2,bm9wZQ==", STANDARD_WEBHOOKS_SIGNATURE))));
+        assert!(!verify(standard_webhook("v2,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE=")));
+        assert!(!verify(standard_webhook("v1,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE")));
+        assert!(!verify(standard_webhook("v1,Ceo5qEr07ixe2NLpvHk3FH9bwy/WavXrAFQ/9tdO6mc=")));
+        assert!(!verify(standard_webhook("")));
+
+        // Everything that is signed has to match.
+        let (headers, _) = standard_webhook(STANDARD_WEBHOOKS_SIGNATURE);
+        assert!(!verify((headers, Bytes::from_static(br#"{"test": 2432232315}"#))));
+        let (mut headers, body) = standard_webhook(STANDARD_WEBHOOKS_SIGNATURE);
+        headers.insert("webhook-id", HeaderValue::from_static("msg_p5jXN8AQM9LWM0D4loKWxJel"));
+        assert!(!verify((headers, body)));
+        let (mut headers, body) = standard_webhook(STANDARD_WEBHOOKS_SIGNATURE);
+        headers.remove("webhook-id");
+        assert!(!verify((headers, body)));
+
+        // The secret may also be given without its prefix, but not in any other form.
+        let secrets = [secret("secret", "MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw", None)];
+        let (headers, body) = standard_webhook(STANDARD_WEBHOOKS_SIGNATURE);
+        assert!(Provider::StandardWebhooks.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_some());
+        let secrets = [secret("secret", "not base64!", None)];
+        assert!(Provider::StandardWebhooks.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_none());
+    }
+
+    #[test]
+    fn standard_webhooks_tolerance() {
+        let signed_at = SystemTime::UNIX_EPOCH + Duration::from_secs(STANDARD_WEBHOOKS_TIMESTAMP);
+        let secrets = [secret("secret", STANDARD_WEBHOOKS_SECRET, None)];
+        let (headers, body) = standard_webhook(STANDARD_WEBHOOKS_SIGNATURE);
+        let verify = |now| Provider::StandardWebhooks.verify(&secrets, false, TOLERANCE, now, &headers, &body).is_some();
+
+        assert!(verify(signed_at + TOLERANCE));
+        assert!(verify(signed_at - TOLERANCE));
+        assert!(!verify(signed_at + TOLERANCE + Duration::from_secs(1)));
+        assert!(!verify(signed_at - TOLERANCE - Duration::from_secs(1)));
+        assert!(!verify(SystemTime::now()));
+
+        let mut headers = headers.clone();
+        headers.insert("webhook-timestamp", HeaderValue::from_static("yesterday"));
+        assert!(Provider::StandardWebhooks.verify(&secrets, false, TOLERANCE, signed_at, &headers, &body).is_none());
+
+        // Timestamps beyond what `SystemTime` can represent are rejected rather than overflowing.
+        for timestamp in ["18446744073709551615", "9223372036854775808"] {
+            headers.insert("webhook-timestamp", HeaderValue::from_static(timestamp));
+            assert!(Provider::StandardWebhooks.verify(&secrets, false, TOLERANCE, signed_at, &headers, &body).is_none());
+        }
+    }
+
+    #[test]
+    fn standard_webhooks_event() {
+        let body = br#"{"type": "invoice.paid", "timestamp": "2022-11-03T20:26:10.344522Z", "data": {}}"#;
+        assert_eq!(Provider::StandardWebhooks.event(&HeaderMap::new(), body), Ok("invoice.paid".to_string()));
+        assert!(Provider::StandardWebhooks.event(&HeaderMap::new(), b"{}").is_err());
+    }
+
+    #[test]
+    fn public_key_signature() {
+        let provider = serde_json::from_str::<Provider>(r#"{"public_key": {
+            "key": "-----BEGIN PUBLIC KEY-----\nMCowBQYDK2VwAyEADMjTYFJmM2+4hjBcppivNKD9GGSnlScFR+6tPG7kVik=\n-----END PUBLIC KEY-----\n",
+            "header": "X-Signature-Ed25519",
+            "encoding": "base64",
+            "timestamp_header": "X-Signature-Timestamp",
+            "event_pointer": "/event"
+        }}"#).unwrap();
+        let signed_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        let body = Bytes::from_static(br#"{"event": "deploy", "ref": "refs/heads/main"}"#);
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature-Ed25519", HeaderValue::from_static("/bqkBCc+cpYGp7d+/uuW/u5nnBKIqYttAMNkTjuNv/DkD4Da3Nb97PAhavODesyABNPBGRCvYLYMOWuJCgWbCA=="));
+        headers.insert("X-Signature-Timestamp", HeaderValue::from_static("1700000000"));
+
+        assert_eq!(provider.event(&headers, &body), Ok("deploy".to_string()));
+        assert!(!provider.uses_secrets());
+        assert_matches!(provider.verify(&[], false, TOLERANCE, signed_at, &headers, &body), Some(Verified::PublicKey));
+        assert!(provider.verify(&[], false, TOLERANCE, signed_at, &headers, &body[1..].to_vec().into()).is_none());
+
+        // The timestamp is signed as well, and must be recent.
+        assert!(provider.verify(&[], false, TOLERANCE, signed_at + 2 * TOLERANCE, &headers, &body).is_none());
+        headers.insert("X-Signature-Timestamp", HeaderValue::from_static("1700000001"));
+        assert!(provider.verify(&[], false, TOLERANCE, signed_at, &headers, &body).is_none());
+        headers.insert("X-Signature-Timestamp", HeaderValue::from_static("18446744073709551615"));
+        assert!(provider.verify(&[], false, TOLERANCE, signed_at, &headers, &body).is_none());
+        headers.remove("X-Signature-Timestamp");
+        assert!(provider.verify(&[], false, TOLERANCE, signed_at, &headers, &body).is_none());
+    }
+
+    #[test]
+    fn public_key_without_timestamp() {
+        let provider = serde_json::from_str::<Provider>(r#"{"public_key": {
+            "key": "BE41m0lJhgCEYZH962M6Zk0nOhJXfmCmSfazr9SkOJDFbo8L/lKXlGzD3psCx6bRdqzww8WdKhuFcGfUUYmOUhI=",
+            "header": "X-Signature",
+            "prefix": "ecdsa="
+        }}"#).unwrap();
+        let body = Bytes::from_static(br#"{"event": "deploy", "ref": "refs/heads/main"}"#);
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature", HeaderValue::from_static("ecdsa=91e9ef5da1b11a70ba745e28a831b1d4c68c42e9dc5f59d3fed642b30263126211414005eb82812d5ba1714c1b606adaf39b72652562217cae5d475fff6eb947"));
+
+        let now = SystemTime::now();
+        assert_eq!(provider.event(&headers, &body), Ok("webhook".to_string()));
+        assert!(provider.verify(&[], false, TOLERANCE, now, &headers, &body).is_some());
+        headers.insert("X-Signature", HeaderValue::from_static("91e9ef5da1b11a70ba745e28a831b1d4c68c42e9dc5f59d3fed642b30263126211414005eb82812d5ba1714c1b606adaf39b72652562217cae5d475fff6eb947"));
+        assert!(provider.verify(&[], false, TOLERANCE, now, &headers, &body).is_none());
+
+        let invalid_key = r#"{"public_key": {"key": "abcd", "header": "X-Signature"}}"#;
+        let err = serde_json::from_str::<Provider>(invalid_key).unwrap_err();
+        assert!(err.to_string().contains("public key of 2 bytes"), "{}", err);
+    }
+}
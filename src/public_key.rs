@@ -0,0 +1,205 @@
+//! Public keys of senders which sign their requests with a private key, so no secret has to be
+//! shared with them.
+
+use crate::provider::decode_hex;
+
+use base64::Engine;
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::SubjectPublicKeyInfoDer;
+use serde::Deserialize;
+use x509_parser::oid_registry::{OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384, OID_SIG_ED25519};
+use x509_parser::prelude::FromDer;
+use x509_parser::x509::SubjectPublicKeyInfo;
+
+use std::fmt::{self, Display};
+
+/// A public key to verify signatures with.
+///
+/// In the configuration, this is written either PEM-encoded (`-----BEGIN PUBLIC KEY-----...`) or
+/// as the raw key in hex or base64. Raw keys are told apart by their length: 32 bytes are an
+/// Ed25519 key, while ECDSA keys must be uncompressed points on P-256 or P-384.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(try_from = "String")]
+pub struct PublicKey {
+    algorithm: KeyAlgorithm,
+    bytes: Vec<u8>,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum KeyAlgorithm {
+    Ed25519,
+    EcdsaP256,
+    EcdsaP384,
+}
+
+impl PublicKey {
+    /// Checks that `signature` was made over `message` with the corresponding private key. ECDSA
+    /// signatures may be either DER-encoded or the two numbers `r` and `s` back to back.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let algorithm: &dyn VerificationAlgorithm = match (self.algorithm, signature.len()) {
+            (KeyAlgorithm::Ed25519, _) => &signature::ED25519,
+            (KeyAlgorithm::EcdsaP256, 64) => &signature::ECDSA_P256_SHA256_FIXED,
+            (KeyAlgorithm::EcdsaP256, _) => &signature::ECDSA_P256_SHA256_ASN1,
+            (KeyAlgorithm::EcdsaP384, 96) => &signature::ECDSA_P384_SHA384_FIXED,
+            (KeyAlgorithm::EcdsaP384, _) => &signature::ECDSA_P384_SHA384_ASN1,
+        };
+        UnparsedPublicKey::new(algorithm, &self.bytes).verify(message, signature).is_ok()
+    }
+
+    fn from_pem(pem: &str) -> Result<PublicKey, PublicKeyError> {
+        let der = SubjectPublicKeyInfoDer::from_pem_slice(pem.as_bytes())
+            .map_err(|e| PublicKeyError::Pem(e.to_string()))?;
+        let (_, info) = SubjectPublicKeyInfo::from_der(&der)
+            .map_err(|e| PublicKeyError::Pem(e.to_string()))?;
+
+        let algorithm = &info.algorithm;
+        let curve = algorithm.parameters.as_ref().and_then(|parameters| parameters.as_oid().ok());
+        let algorithm = if algorithm.algorithm == OID_SIG_ED25519 {
+            KeyAlgorithm::Ed25519
+        } else if algorithm.algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY && curve == Some(OID_EC_P256) {
+            KeyAlgorithm::EcdsaP256
+        } else if algorithm.algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY && curve == Some(OID_NIST_EC_P384) {
+            KeyAlgorithm::EcdsaP384
+        } else {
+            return Err(PublicKeyError::UnsupportedAlgorithm(algorithm.algorithm.to_id_string()));
+        };
+        PublicKey::new(algorithm, info.subject_public_key.data.to_vec())
+    }
+
+    fn from_raw(raw: &str) -> Result<PublicKey, PublicKeyError> {
+        let bytes = decode_hex(raw)
+            .or_else(|| base64::engine::general_purpose::STANDARD.decode(raw).ok())
+            .ok_or(PublicKeyError::Encoding)?;
+        let algorithm = match bytes.len() {
+            32 => KeyAlgorithm::Ed25519,
+            65 => KeyAlgorithm::EcdsaP256,
+            97 => KeyAlgorithm::EcdsaP384,
+            n => return Err(PublicKeyError::Length(n)),
+        };
+        PublicKey::new(algorithm, bytes)
+    }
+
+    fn new(algorithm: KeyAlgorithm, bytes: Vec<u8>) -> Result<PublicKey, PublicKeyError> {
+        let expected = match algorithm {
+            KeyAlgorithm::Ed25519 => 32,
+            KeyAlgorithm::EcdsaP256 => 65,
+            KeyAlgorithm::EcdsaP384 => 97,
+        };
+        // Ring only checks the key when verifying a signature, so we at least make sure the length
+        // is right and ECDSA keys are uncompressed.
+        if bytes.len() != expected || (algorithm != KeyAlgorithm::Ed25519 && bytes[0] != 0x04) {
+            return Err(PublicKeyError::Length(bytes.len()));
+        }
+        Ok(PublicKey { algorithm, bytes })
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = PublicKeyError;
+
+    fn try_from(key: String) -> Result<PublicKey, PublicKeyError> {
+        let key = key.trim();
+        if key.starts_with("-----BEGIN") {
+            PublicKey::from_pem(key)
+        } else {
+            PublicKey::from_raw(key)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PublicKeyError {
+    /// The PEM-encoded key couldn't be decoded.
+    Pem(String),
+
+    /// The key is neither Ed25519 nor ECDSA on P-256 or P-384. Contains the algorithm's OID.
+    UnsupportedAlgorithm(String),
+
+    /// A raw key is neither valid hex nor base64.
+    Encoding,
+
+    /// A raw key has a length which doesn't match any supported algorithm, or an ECDSA key is
+    /// compressed.
+    Length(usize),
+}
+
+impl Display for PublicKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            PublicKeyError::Pem(e) => write!(f, "invalid PEM-encoded public key: {}", e),
+            PublicKeyError::UnsupportedAlgorithm(oid) => write!(f, "unsupported public key algorithm {}", oid),
+            PublicKeyError::Encoding => write!(f, "public key is neither PEM, hex nor base64"),
+            PublicKeyError::Length(n) => write!(f, "public key of {} bytes is neither Ed25519 nor uncompressed ECDSA on P-256 or P-384", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyAlgorithm, PublicKey, PublicKeyError};
+    use crate::provider::decode_hex;
+    use base64::Engine;
+
+    const ED25519_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEADMjTYFJmM2+4hjBcppivNKD9GGSnlScFR+6tPG7kVik=
+-----END PUBLIC KEY-----";
+    const ED25519_RAW: &str = "0cc8d3605266336fb886305ca698af34a0fd1864a795270547eead3c6ee45629";
+    const P256_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAETjWbSUmGAIRhkf3rYzpmTSc6Eld+
+YKZJ9rOv1KQ4kMVujwv+UpeUbMPemwLHptF2rPDDxZ0qG4VwZ9RRiY5SEg==
+-----END PUBLIC KEY-----";
+    const P256_RAW: &str = "BE41m0lJhgCEYZH962M6Zk0nOhJXfmCmSfazr9SkOJDFbo8L/lKXlGzD3psCx6bRdqzww8WdKhuFcGfUUYmOUhI=";
+
+    const MESSAGE: &[u8] = br#"{"event": "deploy", "ref": "refs/heads/main"}"#;
+    const ED25519_SIGNATURE: &str = "51b78e5964b0fb0d5379f9082de0afca412d87f95001538dd957ce6e37145d890aec68f2f3dc6a4a083a604b23d10cc493a2d9fc4b0256c7bf036d337a28db01";
+    const P256_SIGNATURE_DER: &str = "MEUCIQCR6e9dobEacLp0XiioMbHUxoxC6dxfWdP+1kKzAmMSYgIgEUFABeuCgS1boXFMG2Bq2vObcmUlYiF8rl1HX/9uuUc=";
+    const P256_SIGNATURE_FIXED: &str = "91e9ef5da1b11a70ba745e28a831b1d4c68c42e9dc5f59d3fed642b30263126211414005eb82812d5ba1714c1b606adaf39b72652562217cae5d475fff6eb947";
+
+    fn key(key: &str) -> PublicKey {
+        PublicKey::try_from(key.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parse_keys() {
+        assert_eq!(key(ED25519_PEM).algorithm, KeyAlgorithm::Ed25519);
+        assert_eq!(key(ED25519_PEM), key(ED25519_RAW));
+        assert_eq!(key(P256_PEM).algorithm, KeyAlgorithm::EcdsaP256);
+        assert_eq!(key(P256_PEM), key(P256_RAW));
+
+        // An RSA key.
+        let rsa = "-----BEGIN PUBLIC KEY-----
+MFwwDQYJKoZIhvcNAQEBBQADSwAwSAJBAL1LStXKAXguOud1QUyrJssIyqKFWvIN
+ZVtyj26hpM229xOCr2TG607gY8YO5Yubim2pJWY3qXxv8G3KoYX6vtUCAwEAAQ==
+-----END PUBLIC KEY-----";
+        assert!(matches!(PublicKey::try_from(rsa.to_string()), Err(PublicKeyError::UnsupportedAlgorithm(_))));
+        for invalid in [ "-----BEGIN PUBLIC KEY-----\n-----END PUBLIC KEY-----", "not a key", "abcd", ""] {
+            assert!(PublicKey::try_from(invalid.to_string()).is_err(), "{}", invalid);
+        }
+        // A compressed P-256 key.
+        let compressed = "024e359b4949860084 6191fdeb633a664d273a12577e60a649f6b3afd4a43890c5".replace(' ', "");
+        assert!(PublicKey::try_from(compressed).is_err());
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let ed25519 = key(ED25519_PEM);
+        let signature = decode_hex(ED25519_SIGNATURE).unwrap();
+        assert!(ed25519.verify(MESSAGE, &signature));
+        assert!(!ed25519.verify(&MESSAGE[1..], &signature));
+
+        let p256 = key(P256_PEM);
+        let der = base64::engine::general_purpose::STANDARD.decode(P256_SIGNATURE_DER).unwrap();
+        let fixed = decode_hex(P256_SIGNATURE_FIXED).unwrap();
+        assert!(p256.verify(MESSAGE, &der));
+        assert!(p256.verify(MESSAGE, &fixed));
+        assert!(!p256.verify(&MESSAGE[1..], &der));
+        assert!(!p256.verify(MESSAGE, &signature));
+        assert!(!ed25519.verify(MESSAGE, &fixed));
+
+        // RFC 8032, section 7.1, test 2.
+        let rfc = key("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c");
+        let signature = decode_hex("92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00").unwrap();
+        assert!(rfc.verify(&[0x72], &signature));
+    }
+}
@@ -0,0 +1,252 @@
+//! Replacing the running binary without closing the listening sockets, e.g. after an upgrade. The
+//! sockets are passed to the new process the same way systemd would, so it picks them up with
+//! [`systemd_socket::listen_fds`].
+
+use crate::secret::Redacted;
+use crate::systemd_socket::{self, LISTEN_FDS_START};
+
+use nix::libc;
+
+use std::env;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::ptr;
+use std::time::Duration;
+
+/// The listening sockets to pass on to a new process.
+pub struct Handoff {
+    sockets: Vec<(String, OwnedFd)>,
+    watchdog: Option<Duration>,
+    env: Vec<(String, Redacted)>,
+}
+
+impl Handoff {
+    pub fn new() -> Handoff {
+        Handoff {
+            sockets: vec![],
+            watchdog: None,
+            env: vec![],
+        }
+    }
+
+    /// Adds a socket to pass on as `name`. The socket is duplicated, so it stays open for the new
+    /// process even after we stop accepting on it.
+    pub fn add(&mut self, name: &str, fd: BorrowedFd<'_>) -> io::Result<()> {
+        self.sockets.push((name.to_string(), fd.try_clone_to_owned()?));
+        Ok(())
+    }
+
+    /// Passes the watchdog timeout on, since systemd expects the new process to keep sending
+    /// `WATCHDOG=1` once it has taken over.
+    pub fn set_watchdog(&mut self, timeout: Duration) {
+        self.watchdog = Some(timeout);
+    }
+
+    /// Sets the environment variable `name` for the new process only, e.g. for a secret which has
+    /// been removed from our own environment so commands don't inherit it.
+    pub fn set_env(&mut self, name: &str, value: Redacted) {
+        self.env.retain(|(existing, _)| existing != name);
+        self.env.push((name.to_string(), value));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    /// Starts a new instance of this binary with the same arguments, passing on the sockets.
+    pub fn spawn(&self) -> io::Result<Child> {
+        let args = env::args_os().collect::<Vec<_>>();
+        // `current_exe` resolves symlinks, so it would point to the binary we are replacing.
+        // Whatever we were started as is more likely to point to the new one.
+        let program = match args.first() {
+            Some(arg0) if arg0.as_bytes().contains(&b'/') => PathBuf::from(arg0),
+            _ => env::current_exe()?,
+        };
+        self.spawn_program(program.as_os_str(), &args)
+    }
+
+    fn spawn_program<S: AsRef<OsStr>>(&self, program: &OsStr, args: &[S]) -> io::Result<Child> {
+        let mut exec = Exec::new(program, args, self)?;
+        let mut command = Command::new(program);
+        // SAFETY: `Exec::exec` only makes async-signal-safe calls and doesn't allocate.
+        unsafe {
+            command.pre_exec(move || exec.exec());
+        }
+        command.spawn()
+    }
+}
+
+/// Everything needed to exec the new process, prepared before forking.
+///
+/// `LISTEN_PID` has to be the pid of the new process, which is only known after forking. Between
+/// forking and exec we can neither allocate nor modify the environment safely, so `Command::env`
+/// is of no use. Instead we call `execve` ourselves with an environment where there's room left
+/// for the pid.
+struct Exec {
+    path: CString,
+    _argv: Vec<CString>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    _envp: Vec<CString>,
+    envp_ptrs: Vec<*const libc::c_char>,
+    listen_pid: Vec<u8>,
+    fds: Vec<RawFd>,
+    moved_fds: Vec<RawFd>,
+}
+
+// SAFETY: The raw pointers only point into the `CString`s and `listen_pid`, which are owned by the
+// struct itself and never reallocated.
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+impl Exec {
+    fn new<S: AsRef<OsStr>>(program: &OsStr, args: &[S], handoff: &Handoff) -> io::Result<Exec> {
+        let path = cstring(program.as_bytes())?;
+        let argv = args.iter()
+            .map(|arg| cstring(arg.as_ref().as_bytes()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut envp = vec![];
+        for (key, value) in env::vars_os() {
+            let skip = [
+                systemd_socket::VAR_FDS,
+                systemd_socket::VAR_NAMES,
+                systemd_socket::VAR_PID,
+                systemd_socket::VAR_WATCHDOG_USEC,
+                systemd_socket::VAR_WATCHDOG_PID,
+            ];
+            if !skip.iter().any(|var| key == *var) {
+                envp.push(cstring(&[key.as_bytes(), b"=", value.as_bytes()].concat())?);
+            }
+        }
+        let names = handoff.sockets.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        envp.push(cstring(format!("{}={}", systemd_socket::VAR_FDS, names.len()).as_bytes())?);
+        envp.push(cstring(format!("{}={}", systemd_socket::VAR_NAMES, names.join(":")).as_bytes())?);
+        for (name, value) in &handoff.env {
+            envp.push(cstring(&[name.as_bytes(), b"=", value.expose()].concat())?);
+        }
+        if let Some(timeout) = handoff.watchdog {
+            envp.push(cstring(format!("{}={}", systemd_socket::VAR_WATCHDOG_USEC, timeout.as_micros()).as_bytes())?);
+        }
+
+        // Room for the digits of any pid and the terminating NUL.
+        let mut listen_pid = format!("{}=", systemd_socket::VAR_PID).into_bytes();
+        listen_pid.resize(listen_pid.len() + 21, 0);
+
+        let mut argv_ptrs = argv.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+        argv_ptrs.push(ptr::null());
+        let mut envp_ptrs = envp.iter().map(|var| var.as_ptr()).collect::<Vec<_>>();
+        envp_ptrs.push(listen_pid.as_ptr() as *const libc::c_char);
+        envp_ptrs.push(ptr::null());
+
+        let fds = handoff.sockets.iter().map(|(_, fd)| fd.as_raw_fd()).collect::<Vec<_>>();
+        Ok(Exec {
+            path,
+            _argv: argv,
+            argv_ptrs,
+            _envp: envp,
+            envp_ptrs,
+            listen_pid,
+            moved_fds: vec![0; fds.len()],
+            fds,
+        })
+    }
+
+    /// Runs in the forked child. Only returns on error.
+    fn exec(&mut self) -> io::Result<()> {
+        // The sockets need to end up at fds 3, 4, ... which may be taken by other sockets we are
+        // passing on, so we move them out of the way first. The copies are closed by `execve`.
+        let end = LISTEN_FDS_START + self.fds.len() as RawFd;
+        for (fd, moved) in self.fds.iter().zip(self.moved_fds.iter_mut()) {
+            *moved = check(unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, end) })?;
+        }
+        for (target, moved) in (LISTEN_FDS_START..).zip(&self.moved_fds) {
+            // Unlike the original, the duplicate doesn't have `FD_CLOEXEC` set.
+            check(unsafe { libc::dup2(*moved, target) })?;
+        }
+
+        let prefix = systemd_socket::VAR_PID.len() + 1;
+        write_number(&mut self.listen_pid[prefix..], unsafe { libc::getpid() } as u32);
+
+        unsafe { libc::execve(self.path.as_ptr(), self.argv_ptrs.as_ptr(), self.envp_ptrs.as_ptr()) };
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn cstring(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Writes `n` in decimal followed by a NUL to `buf`, without allocating.
+fn write_number(buf: &mut [u8], mut n: u32) {
+    let mut digits = [0; 10];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        n /= 10;
+        len += 1;
+        if n == 0 {
+            break;
+        }
+    }
+    for (dst, src) in buf.iter_mut().zip(digits[..len].iter().rev()) {
+        *dst = *src;
+    }
+    buf[len] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_number, Handoff};
+    use crate::systemd_socket::tests::lock_env;
+    use std::ffi::OsStr;
+    use std::net::TcpListener;
+    use std::os::fd::AsFd;
+
+    #[test]
+    fn number() {
+        let mut buf = [b'x'; 12];
+        write_number(&mut buf, 0);
+        assert_eq!(&buf[..2], b"0\0");
+        write_number(&mut buf, 4194304);
+        assert_eq!(&buf[..8], b"4194304\0");
+        write_number(&mut buf, u32::MAX);
+        assert_eq!(&buf[..11], b"4294967295\0");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sockets_are_passed() {
+        // Other tests expect sockets to get specific fds, so we must not create any concurrently.
+        let _l = lock_env();
+        let listener1 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener2 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut handoff = Handoff::new();
+        handoff.add("first", listener1.as_fd()).unwrap();
+        handoff.add("second", listener2.as_fd()).unwrap();
+        handoff.set_env("WEBHOOK_LISTENER_SECRET", "s3cret".into());
+
+        let script = r#"
+            test "$LISTEN_PID" = "$$" || exit 1
+            test "$LISTEN_FDS" = 2 || exit 2
+            test "$LISTEN_FDNAMES" = first:second || exit 3
+            case "$(readlink /proc/$$/fd/3)" in socket:*) ;; *) exit 4;; esac
+            case "$(readlink /proc/$$/fd/4)" in socket:*) ;; *) exit 5;; esac
+            test "$WEBHOOK_LISTENER_SECRET" = s3cret || exit 6
+        "#;
+        let mut child = handoff.spawn_program(OsStr::new("/bin/sh"), &["sh", "-c", script]).unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(0));
+    }
+}
@@ -0,0 +1,61 @@
+//! A type for credentials, which keeps them out of logs and doesn't leave copies lying around in
+//! memory once they are no longer needed.
+
+use zeroize::Zeroizing;
+
+use std::fmt;
+
+/// A credential, such as the secret shared with GitHub. It never shows up in `Debug` or `Display`
+/// output, and its memory is overwritten with zeros when it is dropped.
+///
+/// The only way to get at the value is [`expose`](Redacted::expose), which should only be called
+/// where the credential is actually used to verify a request.
+#[derive(Clone, Default, PartialEq)]
+pub struct Redacted(Zeroizing<Vec<u8>>);
+
+impl Redacted {
+    pub fn new(value: String) -> Redacted {
+        Redacted::from_bytes(value.into_bytes())
+    }
+
+    pub fn from_bytes(value: Vec<u8>) -> Redacted {
+        Redacted(Zeroizing::new(value))
+    }
+
+    /// Returns the actual value.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&str> for Redacted {
+    fn from(value: &str) -> Redacted {
+        Redacted::new(value.to_string())
+    }
+}
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "[redacted]")
+    }
+}
+
+impl fmt::Display for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "[redacted]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Redacted;
+
+    #[test]
+    fn never_printed() {
+        let secret = Redacted::from("mysecret");
+        assert_eq!(format!("{:?}", secret), "[redacted]");
+        assert_eq!(format!("{}", secret), "[redacted]");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some([redacted])");
+        assert_eq!(secret.expose(), b"mysecret");
+    }
+}
@@ -2,50 +2,57 @@
 //! functions in here are responsible for taking requests from the GitHub API and producing
 //! responses.
 
-use crate::config::{self, Config};
+use crate::config::{self, Profile, Secret};
+use crate::deliveries::DeliveryStore;
+use crate::idle::IdleTracker;
+use crate::provider::Verified;
+use crate::status::Status;
+use crate::systemd_socket;
+use crate::tls::PeerIdentity;
 
 use http_body_util::{combinators::BoxBody, BodyExt, Full, Empty};
 use hyper::body::{Body, Bytes};
-use hyper::header::{HeaderMap, HeaderValue};
 use hyper::{Request, Response, Method, StatusCode};
 
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
-use std::num::ParseIntError;
+use serde::Deserialize;
 
 use tokio::process::Command;
 use tokio::io::AsyncWriteExt;
 use std::io;
 use std::process::{ExitStatus, Stdio};
-
-/// Alias for hasher implementing HMAC-SHA256.
-type HmacSha256 = Hmac<Sha256>;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// Dispatches HTTP requests to different handlers, returning their result.
+///
+/// `peer` is the identity of the client if it authenticated with a TLS client certificate. Commands
+/// keep `idle` busy while they run, so the server doesn't exit before they are done. Deliveries
+/// and commands are reported to systemd through `status`. If given, `deliveries` is used to reject
+/// deliveries which have been received before.
 pub async fn router(
     req: Request<hyper::body::Incoming>,
-    config: &Config,
+    profile: &Profile,
+    peer: Option<&PeerIdentity>,
+    idle: &Arc<IdleTracker>,
+    status: &Arc<Status>,
+    deliveries: Option<&DeliveryStore>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     match (req.method(), req.uri().path()) {
-        (&Method::POST, "/") => handle_webhook_post(req, config).await,
+        (&Method::POST, "/") => handle_webhook_post(req, profile, peer, idle, status, deliveries).await,
         _ => Ok(empty_res(StatusCode::NOT_FOUND)),
     }
 }
 
 async fn handle_webhook_post(
     req: Request<hyper::body::Incoming>,
-    config: &Config,
+    profile: &Profile,
+    peer: Option<&PeerIdentity>,
+    idle: &Arc<IdleTracker>,
+    status: &Arc<Status>,
+    deliveries: Option<&DeliveryStore>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     let (head, body) = req.into_parts();
 
-    // Extract the event type early on. This allows us to exit before doing expensive signature
-    // checking, if the header is missing or invalid ASCII.
-    let event = match head.headers.get("X-GitHub-event").map(HeaderValue::to_str) {
-        Some(Ok(event)) => event,
-        Some(Err(_)) => return Ok(full_res("Invalid ASCII in header: X-GitHub-Event", StatusCode::BAD_REQUEST)),
-        None => return Ok(full_res("Missing header: X-GitHub-Event", StatusCode::BAD_REQUEST)),
-    };
-
     // Read entire body into `Bytes`. We have to set an upper limit to protect the server from
     // massive allocations.
     let upper = body.size_hint().upper().unwrap_or(u64::MAX);
@@ -55,17 +62,100 @@ async fn handle_webhook_post(
     }
     let body = body.collect().await?.to_bytes();
 
+    // Extract the event type before doing expensive signature checking, so we can exit early if
+    // it's missing or invalid. Some providers put it in the payload, so this has to wait until
+    // the body is read.
+    let event = match profile.provider.event(&head.headers, &body) {
+        Ok(event) => event,
+        Err(message) => return Ok(full_res(message, StatusCode::BAD_REQUEST)),
+    };
+
     // Now that we have read the entire body, we should validate the signature before proceeding.
-    if !validate_request(&config.secret, &head.headers, &body) {
-        eprintln!("Rejecting request becuase signature is missing or invaldi");
-        return Ok(full_res("Missing or invalid signature", StatusCode::BAD_REQUEST));
+    // Clients which authenticated with a certificate have already proven who they are, so they
+    // don't have to sign their requests.
+    match peer {
+        Some(peer) => println!("Accepting request from client authenticated as {:?}", peer.names),
+        None => {
+            let now = SystemTime::now();
+            let Some((secrets, repository)) = select_secrets(profile, &body) else {
+                eprintln!("Rejecting request because the repository could not be read from the payload");
+                return Ok(full_res("Payload does not name a repository", StatusCode::BAD_REQUEST));
+            };
+            let Some(verified) = profile.provider.verify(secrets, profile.allow_sha1, profile.timestamp_tolerance, now, &head.headers, &body) else {
+                match repository {
+                    Some(repository) => eprintln!("Rejecting request for {} because signature is missing or invalid", repository),
+                    None => eprintln!("Rejecting request becuase signature is missing or invaldi"),
+                }
+                return Ok(full_res("Missing or invalid signature", StatusCode::BAD_REQUEST));
+            };
+            match verified {
+                Verified::Secret(secret) => {
+                    match repository {
+                        Some(repository) => println!("Signature matches secret from {} for {}", secret.source, repository),
+                        None => println!("Signature matches secret from {}", secret.source),
+                    }
+                    if let Some(expires_in) = secret.expires_in(now).filter(|d| *d < config::SECRET_EXPIRY_WARNING) {
+                        eprintln!("warning: secret from {} expires in {}, make sure the sender uses a newer one by then.",
+                                  secret.source, config::format_secs(expires_in));
+                    }
+                },
+                Verified::PublicKey => println!("Signature matches public key"),
+            }
+        },
+    }
+
+    // The payload can be trusted now, including the timestamps in it.
+    if let Some(max_event_age) = profile.max_event_age {
+        match event_time(&body) {
+            Some(time) => {
+                let age = SystemTime::now().duration_since(time).unwrap_or_default();
+                if age > max_event_age {
+                    eprintln!("Rejecting {} event from {} because it is older than {}",
+                              event, humantime::format_rfc3339_seconds(time), humantime::format_duration(max_event_age));
+                    return Ok(full_res("Event is too old", StatusCode::BAD_REQUEST));
+                }
+            },
+            None if profile.reject_undated_events => {
+                eprintln!("Rejecting {} event because its age could not be determined", event);
+                return Ok(full_res("Event has no timestamp", StatusCode::BAD_REQUEST));
+            },
+            None => eprintln!("warning: could not determine the age of {} event, accepting it anyway", event),
+        }
+    }
+
+    let delivery_id = profile.provider.delivery_header()
+        .and_then(|header| head.headers.get(header))
+        .and_then(|hv| hv.to_str().ok());
+
+    // The delivery ID isn't covered by the signature, so anyone with a captured request can send
+    // it again with made up IDs. The store only remembers so many of them for that reason.
+    if let (Some(deliveries), Some(header)) = (deliveries, profile.provider.delivery_header()) {
+        let Some(id) = delivery_id else {
+            return Ok(full_res(format!("Missing header: {}", header), StatusCode::BAD_REQUEST));
+        };
+        if !deliveries.insert(id, SystemTime::now()) {
+            if !deliveries.allow_redeliveries() {
+                eprintln!("Rejecting delivery {} because it has been received before", id);
+                return Ok(full_res("Delivery has been received before", StatusCode::CONFLICT));
+            }
+            println!("Accepting redelivery of {}", id);
+        }
     }
 
-    for command in &config.commands {
-        if command.event == event {
+    status.delivery(&event, delivery_id);
+
+    for command in &profile.commands {
+        // Commands restricted to certain identities only run for clients carrying one of them.
+        let allowed = command.identities.is_empty()
+            || peer.is_some_and(|peer| peer.matches_any(&command.identities));
+        if command.event == event && allowed {
             let command_clone = command.clone();
             let body_clone = body.clone();
+            let busy = idle.busy();
+            let status = status.clone();
+            status.command_started();
             tokio::spawn(async move {
+                let _busy = busy;
                 match run_command(&command_clone, body_clone.as_ref()).await {
                     Ok(s) => match s.code() {
                         Some(code) => println!("Command finished with exit code {}: {:?}", code, command_clone),
@@ -73,6 +163,7 @@ async fn handle_webhook_post(
                     },
                     Err(e) => eprintln!("Failed to spawn command: {:?}\nerror: {}", command_clone, e),
                 }
+                status.command_finished();
             });
         }
     }
@@ -86,6 +177,8 @@ async fn run_command(command: &config::Command, body: &[u8]) -> io::Result<ExitS
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .args(&command.args)
+        // Otherwise commands could send notifications to systemd on our behalf.
+        .env_remove(systemd_socket::VAR_NOTIFY_SOCKET)
         .spawn()?;
 
     // Feed data through stdin. Sure hope whatever a "deadlock" is doesn't happen here.
@@ -93,7 +186,7 @@ async fn run_command(command: &config::Command, body: &[u8]) -> io::Result<ExitS
     child_stdin.write_all(body).await?;
     drop(child_stdin);
 
-    Ok(child.wait().await?)
+    child.wait().await
 }
 
 /// Utility to create an empty response.
@@ -121,35 +214,236 @@ fn full_res<T: Into<Bytes>>(
     response
 }
 
-/// Decodes a string slice into a string of bytes.
-///
-/// Implementation taken from [this stackoverflow post](https://stackoverflow.com/a/52992629).
-fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
-    (0..s.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
-        .collect()
-}
-
-/// Validates the signature that GitHub attaches to events.
-fn validate_request(secret: &String, headers: &HeaderMap<HeaderValue>, body: &Bytes) -> bool {
-    // To verify the authenticity of the event, GitHub attaches a signature of the payload to
-    // every request. We extract the header. The header value will look something like this:
-    //
-    //     x-hub-signature-256: sha256=6803d2a3e495fc4bd286d428ea4b794476a1ff1b72bbea4dfafd2477d5d89188
-    let maybe_signature = headers
-        .get("x-hub-signature-256")
-        .and_then(|hv| hv.to_str().ok())         // HeaderValue => &str
-        .and_then(|s| s.strip_prefix("sha256=")) // sha256=2843i4aklds... => 2843i4aklds...
-        .and_then(|s| decode_hex(s).ok());       // &str -> vec<u8>
-    let signature = match maybe_signature {
-        Some(s) => s,
-        None => return false, // Missing or invalid signature
+/// The parts of a payload which identify the repository an event is about.
+#[derive(Deserialize)]
+struct PayloadOrigin {
+    repository: Option<PayloadRepository>,
+    organization: Option<PayloadOrganization>,
+
+    /// GitLab calls repositories projects.
+    project: Option<PayloadProject>,
+}
+
+#[derive(Deserialize)]
+struct PayloadRepository {
+    /// Missing in GitLab's payloads, which only have a display name here.
+    full_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PayloadProject {
+    path_with_namespace: String,
+}
+
+#[derive(Deserialize)]
+struct PayloadOrganization {
+    login: String,
+}
+
+/// The parts of a payload which tell when the event happened. Which of them are present depends on
+/// the event.
+#[derive(Deserialize)]
+struct PayloadTimes {
+    /// `null` when a branch is deleted.
+    head_commit: Option<PayloadCommit>,
+    pull_request: Option<PayloadUpdated>,
+    workflow_run: Option<PayloadUpdated>,
+}
+
+#[derive(Deserialize)]
+struct PayloadCommit {
+    timestamp: String,
+}
+
+#[derive(Deserialize)]
+struct PayloadUpdated {
+    updated_at: String,
+}
+
+/// Returns the latest of the timestamps in the payload, or `None` if it doesn't have any we know
+/// of.
+fn event_time(body: &[u8]) -> Option<SystemTime> {
+    let times = serde_json::from_slice::<PayloadTimes>(body).ok()?;
+    let timestamps = [
+        times.head_commit.map(|commit| commit.timestamp),
+        times.pull_request.map(|pull_request| pull_request.updated_at),
+        times.workflow_run.map(|workflow_run| workflow_run.updated_at),
+    ];
+    timestamps.iter().flatten().filter_map(|timestamp| parse_timestamp(timestamp)).max()
+}
+
+/// Parses an RFC 3339 timestamp like `2024-09-28T13:47:35+02:00`. `humantime` only understands
+/// UTC, so any other offset is applied by hand.
+fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let split = timestamp.len().checked_sub(6).and_then(|i| timestamp.split_at_checked(i));
+    let is_offset = |offset: &str| matches!(offset.as_bytes(), [b'+' | b'-', b'0'..=b'9', b'0'..=b'9', b':', b'0'..=b'9', b'0'..=b'9']);
+    let Some((local, offset)) = split.filter(|(_, offset)| is_offset(offset)) else {
+        return humantime::parse_rfc3339_weak(timestamp).ok();
     };
+    let hours = offset[1..3].parse::<u64>().ok()?;
+    let minutes = offset[4..].parse::<u64>().ok()?;
+    let local = humantime::parse_rfc3339_weak(local).ok()?;
+    let offset_duration = Duration::from_secs(hours * 60 * 60 + minutes * 60);
+    if offset.starts_with('+') {
+        local.checked_sub(offset_duration)
+    } else {
+        local.checked_add(offset_duration)
+    }
+}
+
+/// Chooses the secrets a request must be signed with. If the repository (or its owner or namespace)
+/// named in the payload, or the organization for events not about a repository, has an entry in
+/// [`repository_secrets`](Profile::repository_secrets), only that secret is accepted and the key
+/// is returned as well. Otherwise, any of the profile's [`secrets`](Profile::secrets) is.
+///
+/// Nothing in the payload can be trusted at this point, but a forged repository name only gets
+/// the request checked against a secret the sender would need to know anyway.
+///
+/// Returns `None` if there are repository secrets but the payload doesn't say which repository it
+/// is about in a way we understand, e.g. because it isn't JSON or has duplicate keys. Falling back
+/// to the shared secrets would let their holders send events about any repository, and whatever
+/// reads the payload later might well see a different repository than we did.
+fn select_secrets<'a>(profile: &'a Profile, body: &[u8]) -> Option<(&'a [Secret], Option<&'a str>)> {
+    if profile.repository_secrets.is_empty() {
+        return Some((&profile.secrets, None));
+    }
+    let origin = serde_json::from_slice::<PayloadOrigin>(body).ok()?;
 
-    // Now we independantly calculate a signature of the payload we just read, using the secret. If
-    // Github computed the signature with the same secret, we should be all good.
-    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
-    mac.update(&body);
-    mac.verify_slice(&signature).is_ok()
+    let full_name = origin.repository.as_ref().and_then(|repository| repository.full_name.as_deref())
+        .or(origin.project.as_ref().map(|project| project.path_with_namespace.as_str()));
+    if origin.repository.is_some() && full_name.is_none() {
+        return None;
+    }
+    // On GitLab, projects can be nested in several levels of groups.
+    let owner = full_name.and_then(|full_name| full_name.rsplit_once('/')).map(|(owner, _)| owner);
+    // The organization is only looked at for events which aren't about a repository at all.
+    // Otherwise, naming an organization with its own secret next to a repository without one
+    // would get the request checked against that secret instead of the shared ones.
+    let organization = origin.organization.as_ref()
+        .filter(|_| origin.repository.is_none() && origin.project.is_none())
+        .map(|organization| organization.login.as_str());
+    for key in [full_name, owner, organization].into_iter().flatten() {
+        if let Some((key, secret)) = profile.repository_secrets.get_key_value(key) {
+            return Some((std::slice::from_ref(secret), Some(key.as_str())));
+        }
+    }
+    Some((&profile.secrets, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_time, parse_timestamp, select_secrets};
+    use crate::config::{Profile, Secret, SecretSource};
+    use crate::provider::Provider;
+    use crate::test_util::{sample_request, secret, TOLERANCE};
+    use std::time::{Duration, SystemTime};
+
+    /// A GitHub profile with `mysecret` as the shared secret.
+    fn profile(repository_secrets: Vec<(&str, Secret)>) -> Profile {
+        Profile {
+            secret_path: None,
+            secret: None,
+            secrets: vec![secret("default", "mysecret", None)],
+            repository_secrets: repository_secrets.into_iter()
+                .map(|(key, secret)| (key.to_string(), secret))
+                .collect(),
+            provider: Provider::Github,
+            allow_sha1: false,
+            timestamp_tolerance: TOLERANCE,
+            max_event_age: None,
+            reject_undated_events: false,
+            commands: vec![],
+        }
+    }
+
+    #[test]
+    fn secrets_selected_by_repository() {
+        let profile = profile(vec![
+            ("octo-org/hello-world", secret("hello-world", "hellosecret", None)),
+            ("octo-org", secret("octo-org", "orgsecret", None)),
+            ("other-org", secret("other-org", "othersecret", None)),
+        ]);
+        let select = |body: &str| {
+            let (secrets, key) = select_secrets(&profile, body.as_bytes()).expect("repository is known");
+            let names = secrets.iter().map(|secret| match &secret.source {
+                SecretSource::Env(name) => name.as_str(),
+                source => panic!("unexpected source {}", source),
+            });
+            (names.collect::<Vec<_>>(), key)
+        };
+        let rejected = |body: &str| select_secrets(&profile, body.as_bytes()).is_none();
+
+        assert_eq!(select(r#"{"repository": {"full_name": "octo-org/hello-world"}}"#),
+                   (vec!["hello-world"], Some("octo-org/hello-world")));
+        assert_eq!(select(r#"{"repository": {"full_name": "octo-org/spoon-knife"}}"#),
+                   (vec!["octo-org"], Some("octo-org")));
+        assert_eq!(select(r#"{"organization": {"login": "other-org"}, "action": "member_added"}"#),
+                   (vec!["other-org"], Some("other-org")));
+        assert_eq!(select(r#"{"repository": {"full_name": "someone/else"}}"#), (vec!["default"], None));
+        // An organization named next to a repository without its own secret doesn't count, or it
+        // could be used to pick a secret the sender knows.
+        assert_eq!(select(r#"{"repository": {"full_name": "someone/else"}, "organization": {"login": "other-org"}}"#),
+                   (vec!["default"], None));
+        assert_eq!(select(r#"{"project": {"path_with_namespace": "someone/else"}, "organization": {"login": "other-org"}}"#),
+                   (vec!["default"], None));
+        assert_eq!(select(r#"{"repository": {"full_name": "octo-org/spoon-knife"}, "organization": {"login": "octo-org"}}"#),
+                   (vec!["octo-org"], Some("octo-org")));
+
+        // Payloads we can't make sense of are rejected instead of being checked against the
+        // shared secrets. Commands would likely see the last of duplicate keys, for example.
+        assert!(rejected("payload=%7B%7D"));
+        assert!(rejected(r#"{"repository": {"full_name": "x/y"}, "repository": {"full_name": "octo-org/hello-world"}}"#));
+        assert!(rejected(r#"{"repository": {"full_name": "x/y", "full_name": "octo-org/hello-world"}}"#));
+        assert!(rejected(r#"{"repository": {"name": "hello-world"}}"#));
+
+        // GitLab's payloads name the project instead.
+        assert_eq!(select(r#"{"project": {"path_with_namespace": "octo-org/hello-world"}, "repository": {"name": "Hello World"}}"#),
+                   (vec!["hello-world"], Some("octo-org/hello-world")));
+        assert_eq!(select(r#"{"project": {"path_with_namespace": "other-org/sub-group/project"}}"#),
+                   (vec!["default"], None));
+        assert_eq!(select(r#"{"project": {"path_with_namespace": "octo-org/sub-group/project"}}"#),
+                   (vec!["default"], None));
+
+        // The sample payload is for `linnnus/webhook-test`, which has no secret of its own.
+        let (headers, body) = sample_request();
+        let (secrets, _) = select_secrets(&profile, &body).unwrap();
+        assert!(Provider::Github.verify(secrets, false, TOLERANCE, SystemTime::now(), &headers, &body).is_some());
+    }
+
+    #[test]
+    fn repository_secret_must_match() {
+        let (headers, body) = sample_request();
+        let profile = profile(vec![("linnnus", secret("linnnus", "othersecret", None))]);
+        // The request is signed with the default secret, which isn't good enough for this owner.
+        let (secrets, key) = select_secrets(&profile, &body).unwrap();
+        assert_eq!(key, Some("linnnus"));
+        assert!(Provider::Github.verify(secrets, false, TOLERANCE, SystemTime::now(), &headers, &body).is_none());
+    }
+
+    #[test]
+    fn timestamps() {
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(parse_timestamp("2024-09-28T11:47:35Z"), at(1727524055));
+        assert_eq!(parse_timestamp("2024-09-28T13:47:35+02:00"), at(1727524055));
+        assert_eq!(parse_timestamp("2024-01-15T08:00:00-05:30"), at(1705325400));
+        assert_eq!(parse_timestamp("2024-09-28T13:47:35.250+02:00"), Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1727524055250)));
+        assert_eq!(parse_timestamp("2024-09-28T13:47:35+2:00"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn event_times() {
+        let body = std::fs::read("examples/sample_push_payload.json").unwrap();
+        assert_eq!(event_time(&body), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1727524055)));
+
+        // The latest timestamp counts.
+        let body = r#"{
+            "pull_request": {"updated_at": "2024-09-28T10:00:00Z"},
+            "workflow_run": {"updated_at": "2024-09-28T12:00:00Z"}
+        }"#;
+        assert_eq!(event_time(body.as_bytes()), parse_timestamp("2024-09-28T12:00:00Z"));
+
+        assert_eq!(event_time(br#"{"ref": "refs/heads/old", "deleted": true, "head_commit": null}"#), None);
+        assert_eq!(event_time(br#"{"zen": "Keep it logically awesome."}"#), None);
+    }
 }
@@ -0,0 +1,147 @@
+//! Reporting of what the server is doing to the service manager, so it shows up in `systemctl
+//! status`. Outside of systemd, this does nothing.
+
+use crate::systemd_socket;
+
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Keeps track of the running commands and the last delivery, reporting them to systemd as a
+/// `STATUS=` line whenever they change.
+pub struct Status {
+    state: Mutex<State>,
+
+    /// Set once another process has taken over, after which we leave systemd alone.
+    handed_over: AtomicBool,
+}
+
+#[derive(Default)]
+struct State {
+    commands_running: usize,
+    last_delivery: Option<String>,
+}
+
+impl Status {
+    pub fn new() -> Status {
+        Status {
+            state: Mutex::new(State::default()),
+            handed_over: AtomicBool::new(false),
+        }
+    }
+
+    /// Records that a delivery of `event` has been accepted. `delivery_id` is the value of the
+    /// `X-GitHub-Delivery` header, if present.
+    pub fn delivery(&self, event: &str, delivery_id: Option<&str>) {
+        let delivery = format_delivery(event, delivery_id);
+        self.update(|state| state.last_delivery = Some(delivery));
+    }
+
+    pub fn command_started(&self) {
+        self.update(|state| state.commands_running += 1);
+    }
+
+    pub fn command_finished(&self) {
+        self.update(|state| state.commands_running -= 1);
+    }
+
+    /// Sends `READY=1` along with the current status.
+    pub fn ready(&self) {
+        self.notify(&format!("READY=1\nSTATUS={}", self.state.lock().unwrap()));
+    }
+
+    /// Sends `STOPPING=1`, along with a status line explaining why.
+    pub fn stopping(&self, reason: &str) {
+        self.notify(&format!("STOPPING=1\nSTATUS={}", reason));
+    }
+
+    /// Lets any process of the service send notifications, so the process we are about to hand
+    /// over to can report before it is the main process. This lasts until
+    /// [`hand_over`](Status::hand_over) or [`cancel_hand_over`](Status::cancel_hand_over).
+    pub fn prepare_hand_over(&self) {
+        self.notify("NOTIFYACCESS=all");
+    }
+
+    /// Only accepts notifications from the main process again, after starting the new process
+    /// failed.
+    pub fn cancel_hand_over(&self) {
+        self.notify("NOTIFYACCESS=main");
+    }
+
+    /// Tells systemd that the process `pid` is the main process of the service from now on. Since
+    /// the status is then up to the new process, nothing is sent anymore after this.
+    pub fn hand_over(&self, pid: u32) {
+        self.notify(&format!("MAINPID={}\nNOTIFYACCESS=main", pid));
+        self.handed_over.store(true, Ordering::Relaxed);
+    }
+
+    fn update<F: FnOnce(&mut State)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        self.notify(&format!("STATUS={}", state));
+    }
+
+    fn notify(&self, state: &str) {
+        if self.handed_over.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = systemd_socket::notify(false, state) {
+            eprintln!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+fn format_delivery(event: &str, delivery_id: Option<&str>) -> String {
+    match delivery_id {
+        // The full ID is a GUID, which is a bit much for a status line.
+        Some(id) => format!("{}@{}", event, id.get(..8).unwrap_or(id)),
+        None => event.to_string(),
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self.commands_running {
+            0 => write!(f, "No commands running")?,
+            1 => write!(f, "1 command running")?,
+            n => write!(f, "{} commands running", n)?,
+        }
+        match &self.last_delivery {
+            Some(delivery) => write!(f, ", last delivery {}", delivery),
+            None => write!(f, ", no deliveries yet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_delivery, State};
+
+    // These tests don't go through `Status`, since that would send notifications to whatever
+    // `$NOTIFY_SOCKET` the other tests happen to have set up.
+
+    #[test]
+    fn status_line() {
+        let state = State::default();
+        assert_eq!(state.to_string(), "No commands running, no deliveries yet");
+
+        let state = State {
+            commands_running: 1,
+            last_delivery: Some("push@72d3162e".to_string()),
+        };
+        assert_eq!(state.to_string(), "1 command running, last delivery push@72d3162e");
+
+        let state = State {
+            commands_running: 3,
+            last_delivery: Some("ping".to_string()),
+        };
+        assert_eq!(state.to_string(), "3 commands running, last delivery ping");
+    }
+
+    #[test]
+    fn delivery_is_shortened() {
+        assert_eq!(format_delivery("push", Some("72d3162e-cc78-11e3-81ab-4c9367dc0958")), "push@72d3162e");
+        assert_eq!(format_delivery("push", Some("abc123")), "push@abc123");
+        assert_eq!(format_delivery("ping", None), "ping");
+    }
+}
@@ -1,6 +1,7 @@
-//! `systemd_socket` implements the daemon side of the socket activation. The interface is similar
-//! to the one provided by the systemd/sd-daemon library, but adjusted for easier usage in rust. It
-//! relies on `nix` for all low-level operations. All checks are ported from the systemd code.
+//! `systemd_socket` implements the daemon side of the socket activation and of the notification
+//! protocol (see [`notify`] and [`watchdog_enabled`]). The interface is similar to the one
+//! provided by the systemd/sd-daemon library, but adjusted for easier usage in rust. It relies on
+//! `nix` for all low-level operations. All checks are ported from the systemd code.
 //!
 //! Enums required for socket type (`SockType`) and address family (`AddressFamily`) are reexported
 //! from nix.
@@ -22,17 +23,22 @@ use std::convert::From;
 use std::env;
 use std::error::Error as StdError;
 use std::fmt;
+use std::io::IoSlice;
 use std::num::ParseIntError;
 use std::os::unix::io::{OwnedFd, RawFd};
 use std::os::fd::{AsFd, AsRawFd, FromRawFd};
 use std::path;
+use std::time::Duration;
 
 pub use nix::sys::socket::SockType;
 pub use nix::sys::socket::AddressFamily;
 
-const VAR_FDS: &'static str = "LISTEN_FDS";
-const VAR_NAMES: &'static str = "LISTEN_FDNAMES";
-const VAR_PID: &'static str = "LISTEN_PID";
+pub const VAR_FDS: &str = "LISTEN_FDS";
+pub const VAR_NAMES: &str = "LISTEN_FDNAMES";
+pub const VAR_PID: &str = "LISTEN_PID";
+pub const VAR_NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+pub const VAR_WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+pub const VAR_WATCHDOG_PID: &str = "WATCHDOG_PID";
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -45,27 +51,34 @@ pub enum Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            Error::Var(e) => write!(f, "{}: {}", self.description_str(), e),
+            Error::Parse(e) => write!(f, "{}: {}", self.description_str(), e),
+            Error::Nix(e) => write!(f, "{}: {}", self.description_str(), e),
+            _ => write!(f, "{}", self.description_str()),
+        }
     }
 }
 
-impl StdError for Error {
-    fn description(&self) -> &str {
+impl Error {
+    fn description_str(&self) -> &str {
         match self {
-            &Error::InvalidVariableValue => "Environment variable could not be parsed",
-            &Error::DifferentProcess =>
+            Error::InvalidVariableValue => "Environment variable could not be parsed",
+            Error::DifferentProcess =>
                 "Environment variables are meant for a different process (pid mismatch)",
-            &Error::Var(_) => "Required environment variable missing or unreadable",
-            &Error::Parse(_) => "Could not parse number in 'LISTEN_FDS'",
-            &Error::Nix(_) => "Calling system function on socket failed",
+            Error::Var(_) => "Required environment variable missing or unreadable",
+            Error::Parse(_) => "Could not parse number in environment variable",
+            Error::Nix(_) => "Calling system function on socket failed",
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn StdError> {
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match self {
-            &Error::Var(ref e) => Some(e),
-            &Error::Parse(ref e) => Some(e),
-            &Error::Nix(ref e) => Some(e),
+            Error::Var(e) => Some(e),
+            Error::Parse(e) => Some(e),
+            Error::Nix(e) => Some(e),
             _ => None,
         }
     }
@@ -93,7 +106,7 @@ impl From<nix::Error> for Error {
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Number of the first passed file descriptor
-const LISTEN_FDS_START: RawFd = 3;
+pub const LISTEN_FDS_START: RawFd = 3;
 
 fn unset_all_env() {
     env::remove_var(VAR_PID);
@@ -101,6 +114,91 @@ fn unset_all_env() {
     env::remove_var(VAR_NAMES);
 }
 
+/// Sends a notification to the service manager, e.g. `READY=1` or `STATUS=Doing stuff`. Several
+/// assignments can be sent at once by separating them with newlines. See `sd_notify(3)` for the
+/// full list.
+///
+/// Returns `Ok(false)` if `$NOTIFY_SOCKET` is unset, i.e. if the service manager doesn't expect
+/// notifications. Removes the variable from the environment if `unset_environment` is `true`.
+pub fn notify(unset_environment: bool, state: &str) -> Result<bool> {
+    notify_with_fds(unset_environment, state, &[])
+}
+
+/// Like [`notify`], but also sends the file descriptors `fds` along. Together with `FDSTORE=1`,
+/// this stores them with the service manager, which passes them back on the next start like
+/// sockets from socket activation (see [`listen_fds_with_names`]). `FDNAME=` sets their name.
+pub fn notify_with_fds(unset_environment: bool, state: &str, fds: &[RawFd]) -> Result<bool> {
+    let socket_path = match env::var(VAR_NOTIFY_SOCKET) {
+        Ok(path) => path,
+        Err(env::VarError::NotPresent) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if unset_environment {
+        env::remove_var(VAR_NOTIFY_SOCKET);
+    }
+
+    let addr = notify_socket_addr(&socket_path)?;
+    let fd = socket::socket(AddressFamily::Unix, SockType::Datagram, socket::SockFlag::SOCK_CLOEXEC, None)?;
+    let iov = [IoSlice::new(state.as_bytes())];
+    let rights = [socket::ControlMessage::ScmRights(fds)];
+    let cmsgs = if fds.is_empty() { &[][..] } else { &rights[..] };
+    socket::sendmsg(fd.as_raw_fd(), &iov, cmsgs, socket::MsgFlags::empty(), Some(&addr))?;
+    Ok(true)
+}
+
+/// Returns the watchdog timeout if the service manager expects `WATCHDOG=1` notifications from
+/// this process, or `None` if it doesn't. Like `sd_watchdog_enabled(3)`, this checks
+/// `$WATCHDOG_PID` (if set) against our pid. Removes `$WATCHDOG_USEC` and `$WATCHDOG_PID` from the
+/// environment if `unset_environment` is `true`.
+pub fn watchdog_enabled(unset_environment: bool) -> Result<Option<Duration>> {
+    let result = watchdog_timeout();
+    if unset_environment {
+        env::remove_var(VAR_WATCHDOG_USEC);
+        env::remove_var(VAR_WATCHDOG_PID);
+    }
+    result
+}
+
+fn watchdog_timeout() -> Result<Option<Duration>> {
+    let usec_str = match env::var(VAR_WATCHDOG_USEC) {
+        Ok(usec) => usec,
+        Err(env::VarError::NotPresent) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let usec: u64 = usec_str.parse()?;
+    if usec == 0 {
+        return Err(Error::InvalidVariableValue);
+    }
+
+    match env::var(VAR_WATCHDOG_PID) {
+        Ok(pid_str) => {
+            let pid = Pid::from_raw(pid_str.parse()?);
+            if pid != nix::unistd::getpid() {
+                return Ok(None);
+            }
+        },
+        Err(env::VarError::NotPresent) => {},
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(Some(Duration::from_micros(usec)))
+}
+
+/// Parses the value of `$NOTIFY_SOCKET`, which is either an absolute path or, if it starts with
+/// `@`, the name of a socket in the abstract namespace.
+fn notify_socket_addr(socket_path: &str) -> Result<socket::UnixAddr> {
+    if let Some(_name) = socket_path.strip_prefix('@') {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return Ok(socket::UnixAddr::new_abstract(_name.as_bytes())?);
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        return Err(Error::InvalidVariableValue);
+    }
+    if socket_path.starts_with('/') {
+        return Ok(socket::UnixAddr::new(socket_path)?);
+    }
+    Err(Error::InvalidVariableValue)
+}
+
 /// Returns the file descriptors passed in by init process. Removes the `$LISTEN_FDS` and
 /// `$LISTEN_PID` variables from the environment if `unset_environment` is `true`.
 pub fn listen_fds(unset_environment: bool) -> Result<Vec<OwnedFd>> {
@@ -326,14 +424,16 @@ pub fn is_socket_unix<T: AsFd>(fd: &T, socktype: Option<SockType>, listening: Op
 //}
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use ::nix;
     use ::lazy_static::lazy_static;
     use ::std::env;
     use ::std::os::unix::io::OwnedFd;
+    use ::std::os::unix::net::UnixDatagram;
     use ::std::os::fd::{AsRawFd, FromRawFd};
     use ::std::sync::{Mutex,MutexGuard};
     use ::std::mem;
+    use ::std::time::Duration;
 
     // Even with one -j1, cargo runs multiple tests at once.  That doesn't work with environment
     // variables, or specific socket ordering, so mutexes are required.
@@ -341,7 +441,7 @@ mod tests {
         static ref LOCK: Mutex<()> = Mutex::new(());
     }
 
-    fn lock_env<'a>() -> MutexGuard<'a, ()> {
+    pub(crate) fn lock_env<'a>() -> MutexGuard<'a, ()> {
         // SAFETY: We can ignore `PoisonError`s since the ressource we are locking is just `()`.
         // See: <https://stackoverflow.com/a/51694631>.
         LOCK.lock().unwrap_or_else(|e| e.into_inner())
@@ -389,7 +489,7 @@ mod tests {
     fn listen_fds_success() {
         let _l = lock_env();
         set_current_pid();
-        let _fd = create_socket_with_fd(3, super::AddressFamily::Inet, super::SockType::Stream);
+        create_socket_with_fd(3, super::AddressFamily::Inet, super::SockType::Stream);
         env::set_var(super::VAR_FDS, "1");
         let fds = super::listen_fds(true).unwrap();
         assert_eq!(fds.len(), 1);
@@ -402,8 +502,8 @@ mod tests {
         set_current_pid();
         env::set_var(super::VAR_FDS, "2");
         env::set_var(super::VAR_NAMES, "a:b");
-        let _fd1 = create_socket_with_fd(3, super::AddressFamily::Inet, super::SockType::Stream);
-        let _fd2 = create_socket_with_fd(4, super::AddressFamily::Inet, super::SockType::Stream);
+        create_socket_with_fd(3, super::AddressFamily::Inet, super::SockType::Stream);
+        create_socket_with_fd(4, super::AddressFamily::Inet, super::SockType::Stream);
         let fds = super::listen_fds_with_names(true).unwrap();
         assert_eq!(fds.len(), 2);
         assert_eq!(fds["a"].as_raw_fd(), 3);
@@ -423,6 +523,118 @@ mod tests {
         assert_eq!(env::var(super::VAR_NAMES), Err(env::VarError::NotPresent));
     }
 
+    #[test]
+    fn notify_without_socket() {
+        let _l = lock_env();
+        env::remove_var(super::VAR_NOTIFY_SOCKET);
+        assert_eq!(super::notify(false, "READY=1"), Ok(false));
+    }
+
+    #[test]
+    fn notify_path() {
+        let _l = lock_env();
+        let path = env::temp_dir().join(format!("webhook-listener-notify-{}.sock", nix::unistd::getpid()));
+        let _ = ::std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        env::set_var(super::VAR_NOTIFY_SOCKET, &path);
+        assert_eq!(super::notify(false, "READY=1\nSTATUS=Running"), Ok(true));
+        assert!(env::var(super::VAR_NOTIFY_SOCKET).is_ok());
+
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=Running");
+
+        assert_eq!(super::notify(true, "STOPPING=1"), Ok(true));
+        assert_eq!(env::var(super::VAR_NOTIFY_SOCKET), Err(env::VarError::NotPresent));
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STOPPING=1");
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn notify_abstract() {
+        use ::std::os::linux::net::SocketAddrExt;
+
+        let _l = lock_env();
+        let name = format!("webhook-listener-notify-{}", nix::unistd::getpid());
+        let addr = ::std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let receiver = UnixDatagram::bind_addr(&addr).unwrap();
+
+        env::set_var(super::VAR_NOTIFY_SOCKET, format!("@{}", name));
+        assert_eq!(super::notify(true, "READY=1"), Ok(true));
+
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn notify_with_fds() {
+        use ::nix::sys::socket::{self, ControlMessageOwned, MsgFlags, SockFlag, UnixAddr};
+
+        let _l = lock_env();
+        let path = env::temp_dir().join(format!("webhook-listener-fdstore-{}.sock", nix::unistd::getpid()));
+        let _ = ::std::fs::remove_file(&path);
+        let receiver = socket::socket(super::AddressFamily::Unix, super::SockType::Datagram, SockFlag::empty(), None).unwrap();
+        socket::bind(receiver.as_raw_fd(), &UnixAddr::new(&path).unwrap()).unwrap();
+
+        let file = open_file();
+        env::set_var(super::VAR_NOTIFY_SOCKET, &path);
+        assert_eq!(super::notify_with_fds(true, "FDSTORE=1\nFDNAME=hosts", &[file.as_raw_fd()]), Ok(true));
+
+        let mut buf = [0; 64];
+        let mut iov = [::std::io::IoSliceMut::new(&mut buf)];
+        let mut cmsg_buf = nix::cmsg_space!([super::RawFd; 1]);
+        let msg = socket::recvmsg::<()>(receiver.as_raw_fd(), &mut iov, Some(&mut cmsg_buf), MsgFlags::empty()).unwrap();
+        let Some(ControlMessageOwned::ScmRights(fds)) = msg.cmsgs().unwrap().next() else {
+            panic!("Expected a file descriptor");
+        };
+        assert_eq!(fds.len(), 1);
+        let (received, len) = (unsafe { OwnedFd::from_raw_fd(fds[0]) }, msg.bytes);
+        let stat = |fd: &OwnedFd| nix::sys::stat::fstat(fd.as_raw_fd()).unwrap();
+        assert_eq!(stat(&received).st_ino, stat(&file).st_ino);
+        assert_eq!(&buf[..len], b"FDSTORE=1\nFDNAME=hosts");
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn notify_invalid_socket() {
+        let _l = lock_env();
+        env::set_var(super::VAR_NOTIFY_SOCKET, "relative/path");
+        assert_eq!(super::notify(true, "READY=1"), Err(super::Error::InvalidVariableValue));
+    }
+
+    #[test]
+    fn watchdog_enabled() {
+        let _l = lock_env();
+        env::remove_var(super::VAR_WATCHDOG_USEC);
+        env::remove_var(super::VAR_WATCHDOG_PID);
+        assert_eq!(super::watchdog_enabled(false), Ok(None));
+
+        env::set_var(super::VAR_WATCHDOG_USEC, "30000000");
+        assert_eq!(super::watchdog_enabled(false), Ok(Some(Duration::from_secs(30))));
+
+        env::set_var(super::VAR_WATCHDOG_PID, format!("{}", nix::unistd::getpid()));
+        assert_eq!(super::watchdog_enabled(true), Ok(Some(Duration::from_secs(30))));
+        assert_eq!(env::var(super::VAR_WATCHDOG_USEC), Err(env::VarError::NotPresent));
+        assert_eq!(env::var(super::VAR_WATCHDOG_PID), Err(env::VarError::NotPresent));
+    }
+
+    #[test]
+    fn watchdog_for_other_process() {
+        let _l = lock_env();
+        env::set_var(super::VAR_WATCHDOG_USEC, "30000000");
+        env::set_var(super::VAR_WATCHDOG_PID, "1");
+        assert_eq!(super::watchdog_enabled(true), Ok(None));
+
+        env::set_var(super::VAR_WATCHDOG_USEC, "0");
+        assert_eq!(super::watchdog_enabled(true), Err(super::Error::InvalidVariableValue));
+    }
+
     #[test]
     fn is_socket() {
         let _l = lock_env();
@@ -456,6 +668,16 @@ mod tests {
         assert!(!super::is_socket_inet(&fd, None, None, None, None).unwrap());
     }
 
+    #[test]
+    fn is_socket_inet6() {
+        let _l = lock_env();
+        let fd = create_socket(super::AddressFamily::Inet6, super::SockType::Stream);
+        assert!(super::is_socket_inet(&fd, None, Some(super::SockType::Stream), None, None).unwrap());
+        assert!(super::is_socket_inet(&fd, Some(super::AddressFamily::Inet6), None, None, None).unwrap());
+        assert!(!super::is_socket_inet(&fd, Some(super::AddressFamily::Inet), None, None, None).unwrap());
+        assert!(!super::is_socket_unix(&fd, None, None, None).unwrap());
+    }
+
     #[test]
     fn is_socket_unix() {
         let _l = lock_env();
@@ -0,0 +1,43 @@
+//! Helpers shared by the tests of several modules.
+
+use crate::config::{Secret, SecretSource};
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue};
+
+use std::time::{Duration, SystemTime};
+
+/// Asserts that an expression matches a pattern, optionally evaluating to an expression using the
+/// bindings of the pattern.
+macro_rules! assert_matches {
+    ( $e:expr , $pat:pat ) => {
+        $crate::test_util::assert_matches!($e, $pat => ())
+    };
+    ( $e:expr , $pat:pat => $c:expr ) => {
+        match $e {
+            $pat => $c,
+            ref e => panic!("assertion failed: `{:?}` does not match `{}`", e, stringify!($pat))
+        }
+    };
+}
+pub(crate) use assert_matches;
+
+/// The default `timestamp_tolerance`.
+pub const TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+/// The signature of `examples/sample_push_payload.json` with the secret `mysecret`.
+pub const SAMPLE_SIGNATURE: &str = "sha256=6803d2a3e495fc4bd286d428ea4b794476a1ff1b72bbea4dfafd2477d5d89188";
+
+/// A secret with the given value, supposedly read from the environment variable `name`.
+pub fn secret(name: &str, value: &str, not_after: Option<SystemTime>) -> Secret {
+    Secret::with_value(SecretSource::Env(name.to_string()), not_after, value)
+}
+
+/// The sample push event, as GitHub would send it.
+pub fn sample_request() -> (HeaderMap<HeaderValue>, Bytes) {
+    let mut headers = HeaderMap::new();
+    headers.insert("X-GitHub-Event", HeaderValue::from_static("push"));
+    headers.insert("X-Hub-Signature-256", HeaderValue::from_static(SAMPLE_SIGNATURE));
+    let body = std::fs::read("examples/sample_push_payload.json").unwrap();
+    (headers, Bytes::from(body))
+}
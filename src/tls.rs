@@ -0,0 +1,430 @@
+//! This module implements TLS termination with rustls. The certificate and private key are
+//! reloaded whenever the files change (or when the process receives `SIGHUP`), so renewing the
+//! certificate doesn't require a restart.
+//!
+//! Optionally, clients can be required to authenticate with a certificate signed by a configured
+//! CA (mutual TLS). The names in that certificate are then made available to the service as a
+//! [`PeerIdentity`].
+
+use crate::config::TlsConfig;
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, ServerConnection, VerifierBuilderError, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig, SignatureScheme};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the certificate files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Resolves the certificate presented to clients, reloading it from disk when it changes.
+#[derive(Debug)]
+pub struct CertReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+
+    /// The certificate and key currently handed to rustls.
+    current: RwLock<Arc<CertifiedKey>>,
+
+    /// Modification times of the certificate and key files as of the last (re)load. Used to
+    /// detect when the files have been replaced.
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertReloader {
+    /// Loads the certificate and key referenced in `config`.
+    pub fn new(config: &TlsConfig, provider: Arc<CryptoProvider>) -> Result<CertReloader, TlsError> {
+        let modified = (modified_time(&config.cert_path), modified_time(&config.key_path));
+        let current = load_certified_key(&config.cert_path, &config.key_path, &provider)?;
+        Ok(CertReloader {
+            cert_path: config.cert_path.clone(),
+            key_path: config.key_path.clone(),
+            provider,
+            current: RwLock::new(Arc::new(current)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Unconditionally reloads the certificate and key. On failure, the previous certificate stays
+    /// in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let modified = (modified_time(&self.cert_path), modified_time(&self.key_path));
+        let new = load_certified_key(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(new);
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Returns true if either file has been modified since the last (re)load.
+    fn has_changed(&self) -> bool {
+        let modified = (modified_time(&self.cert_path), modified_time(&self.key_path));
+        *self.modified.lock().unwrap() != modified
+    }
+
+    /// Reloads the certificate whenever the files change or `SIGHUP` is received. Never returns.
+    pub async fn watch(self: Arc<Self>) -> io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if !self.has_changed() {
+                        continue;
+                    }
+                    println!("TLS certificate files changed. Reloading.");
+                },
+                _ = hangup.recv() => {
+                    println!("Received SIGHUP. Reloading TLS certificate.");
+                },
+            }
+
+            match self.reload() {
+                Ok(()) => println!("Reloaded TLS certificate from {}", self.cert_path.display()),
+                Err(e) => eprintln!("Failed to reload TLS certificate, keeping the old one: {}", e),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Creates an acceptor which wraps connections in TLS, using the certificate from `reloader`. If
+/// [`client_ca_path`](TlsConfig::client_ca_path) is set, clients must present a certificate signed
+/// by one of those CAs.
+pub fn acceptor(
+    config: &TlsConfig,
+    reloader: Arc<CertReloader>,
+    provider: Arc<CryptoProvider>,
+) -> Result<TlsAcceptor, TlsError> {
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::Rustls)?;
+
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => {
+            let verifier = client_verifier(ca_path, &config.allowed_clients, provider)?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_cert_resolver(reloader);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Creates a verifier accepting client certificates signed by any of the CAs in `ca_path`. If
+/// `allowed_clients` is non-empty, the certificate must also carry one of those names.
+fn client_verifier(
+    ca_path: &Path,
+    allowed_clients: &[String],
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path).map_err(|e| TlsError::Pem(ca_path.to_path_buf(), e))? {
+        let cert = cert.map_err(|e| TlsError::Pem(ca_path.to_path_buf(), e))?;
+        roots.add(cert).map_err(TlsError::Rustls)?;
+    }
+    if roots.is_empty() {
+        return Err(TlsError::NoCertificates(ca_path.to_path_buf()));
+    }
+
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(TlsError::ClientVerifier)?;
+
+    if allowed_clients.is_empty() {
+        Ok(verifier)
+    } else {
+        Ok(Arc::new(AllowlistVerifier {
+            inner: verifier,
+            allowed: allowed_clients.to_vec(),
+        }))
+    }
+}
+
+/// The identity of a client which authenticated itself with a certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerIdentity {
+    /// The common name(s) of the certificate's subject, followed by the DNS names, email addresses
+    /// and URIs listed as subject alternative names.
+    pub names: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// Extracts the identity from a DER-encoded certificate.
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> Option<PeerIdentity> {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+
+        let mut names = cert.subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => {
+                        names.push(s.to_string())
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        Some(PeerIdentity { names })
+    }
+
+    /// Returns the identity of the client on the other end of `conn`, if it presented a
+    /// certificate.
+    pub fn from_connection(conn: &ServerConnection) -> Option<PeerIdentity> {
+        conn.peer_certificates()?.first().and_then(PeerIdentity::from_certificate)
+    }
+
+    /// Returns true if any of the names in this identity appear in `names`.
+    pub fn matches_any(&self, names: &[String]) -> bool {
+        self.names.iter().any(|name| names.contains(name))
+    }
+}
+
+/// Wraps another verifier, additionally requiring that the client's certificate carries one of
+/// the allowed names.
+#[derive(Debug)]
+struct AllowlistVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    allowed: Vec<String>,
+}
+
+impl ClientCertVerifier for AllowlistVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self.inner.verify_client_cert(end_entity, intermediates, now)?;
+        match PeerIdentity::from_certificate(end_entity) {
+            Some(identity) if identity.matches_any(&self.allowed) => Ok(verified),
+            Some(identity) => {
+                eprintln!("Rejecting client certificate not in allowlist: {:?}", identity.names);
+                Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+            },
+            None => Err(rustls::Error::InvalidCertificate(CertificateError::BadEncoding)),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path, provider: &CryptoProvider) -> Result<CertifiedKey, TlsError> {
+    let cert_chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem(cert_path.to_path_buf(), e))?;
+    if cert_chain.is_empty() {
+        return Err(TlsError::NoCertificates(cert_path.to_path_buf()));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| TlsError::Pem(key_path.to_path_buf(), e))?;
+
+    CertifiedKey::from_der(cert_chain, key, provider).map_err(TlsError::Rustls)
+}
+
+/// Errors that can occur when setting up TLS.
+#[derive(Debug)]
+pub enum TlsError {
+    /// Reading or decoding a PEM file failed, e.g. because it doesn't exist or contains no key.
+    Pem(PathBuf, pem::Error),
+    /// The certificate file didn't contain any certificates.
+    NoCertificates(PathBuf),
+    /// Rustls rejected the configuration, e.g. because the key doesn't match the certificate.
+    Rustls(rustls::Error),
+    /// The client CA bundle could not be used to verify client certificates.
+    ClientVerifier(VerifierBuilderError),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            TlsError::Pem(path, e) => write!(f, "error reading {}: {}", path.display(), e),
+            TlsError::NoCertificates(path) => write!(f, "no certificates found in {}", path.display()),
+            TlsError::Rustls(e) => write!(f, "invalid TLS configuration: {}", e),
+            TlsError::ClientVerifier(e) => write!(f, "invalid client CA configuration: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_verifier, CertReloader, PeerIdentity, TlsError};
+    use crate::config::TlsConfig;
+    use rustls::crypto::ring;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, UnixTime};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn example_config(cert: &str, key: &str) -> TlsConfig {
+        TlsConfig {
+            cert_path: PathBuf::from("examples/tls").join(cert),
+            key_path: PathBuf::from("examples/tls").join(key),
+            client_ca_path: None,
+            allowed_clients: vec![],
+        }
+    }
+
+    fn example_certificate(name: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(Path::new("examples/tls").join(name)).unwrap()
+    }
+
+    #[test]
+    fn load_example_certificate() {
+        let config = example_config("cert.pem", "key.pem");
+        let reloader = CertReloader::new(&config, Arc::new(ring::default_provider())).expect("valid certificate");
+        assert_eq!(reloader.current.read().unwrap().cert.len(), 1);
+        assert!(!reloader.has_changed());
+    }
+
+    #[test]
+    fn mismatched_key_gives_error() {
+        let config = example_config("cert.pem", "other-key.pem");
+        let result = CertReloader::new(&config, Arc::new(ring::default_provider()));
+        assert!(matches!(result, Err(TlsError::Rustls(_))), "expected rustls error, got {:?}", result);
+    }
+
+    #[test]
+    fn missing_file_gives_error() {
+        let config = example_config("does-not-exist.pem", "key.pem");
+        let result = CertReloader::new(&config, Arc::new(ring::default_provider()));
+        assert!(matches!(result, Err(TlsError::Pem(..))), "expected PEM error, got {:?}", result);
+
+        // A certificate isn't a private key.
+        let config = example_config("cert.pem", "cert.pem");
+        let result = CertReloader::new(&config, Arc::new(ring::default_provider()));
+        assert!(matches!(result, Err(TlsError::Pem(..))), "expected PEM error, got {:?}", result);
+    }
+
+    #[test]
+    fn reload_picks_up_new_certificate() {
+        let dir = std::env::temp_dir().join(format!("webhook-listener-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            client_ca_path: None,
+            allowed_clients: vec![],
+        };
+        fs::copy("examples/tls/cert.pem", &config.cert_path).unwrap();
+        fs::copy("examples/tls/key.pem", &config.key_path).unwrap();
+
+        let reloader = CertReloader::new(&config, Arc::new(ring::default_provider())).expect("valid certificate");
+        let old_cert = reloader.current.read().unwrap().cert.clone();
+
+        fs::copy("examples/tls/other-cert.pem", &config.cert_path).unwrap();
+        fs::copy("examples/tls/other-key.pem", &config.key_path).unwrap();
+        assert!(reloader.has_changed());
+        reloader.reload().expect("valid certificate");
+        assert!(!reloader.has_changed());
+        assert_ne!(reloader.current.read().unwrap().cert, old_cert);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn peer_identity_from_certificate() {
+        let identity = PeerIdentity::from_certificate(&example_certificate("client-cert.pem"));
+        let expected_identity = PeerIdentity {
+            names: vec![
+                "deploy-bot".to_string(),
+                "deploy-bot.internal".to_string(),
+                "ops@example.com".to_string(),
+            ],
+        };
+        assert_eq!(identity, Some(expected_identity));
+    }
+
+    #[test]
+    fn client_verifier_checks_ca() {
+        let ca_path = Path::new("examples/tls/client-ca.pem");
+        let verifier = client_verifier(ca_path, &[], Arc::new(ring::default_provider())).unwrap();
+        assert!(verifier.client_auth_mandatory());
+
+        let result = verifier.verify_client_cert(&example_certificate("client-cert.pem"), &[], UnixTime::now());
+        assert!(result.is_ok(), "expected client certificate to be accepted, got {:?}", result);
+
+        // This one is self-signed rather than signed by the client CA.
+        let result = verifier.verify_client_cert(&example_certificate("other-cert.pem"), &[], UnixTime::now());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn client_verifier_checks_allowlist() {
+        let ca_path = Path::new("examples/tls/client-ca.pem");
+        let client_cert = example_certificate("client-cert.pem");
+
+        let allowed = vec!["deploy-bot.internal".to_string()];
+        let verifier = client_verifier(ca_path, &allowed, Arc::new(ring::default_provider())).unwrap();
+        assert!(verifier.verify_client_cert(&client_cert, &[], UnixTime::now()).is_ok());
+
+        let allowed = vec!["someone-else".to_string()];
+        let verifier = client_verifier(ca_path, &allowed, Arc::new(ring::default_provider())).unwrap();
+        assert!(verifier.verify_client_cert(&client_cert, &[], UnixTime::now()).is_err());
+    }
+}
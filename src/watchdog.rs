@@ -0,0 +1,138 @@
+//! Keeping systemd's watchdog happy, as long as we are actually doing fine. If the runtime gets
+//! stuck, we stop sending `WATCHDOG=1` and systemd restarts us (given `WatchdogSec=` is set).
+
+use crate::systemd_socket;
+
+use tokio::sync::watch;
+use tokio::time;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Pings every registered [`Heartbeat`] and only reports to systemd if all of them answered the
+/// previous ping.
+///
+/// Answering requires the task holding the heartbeat to be polled, so this proves that the accept
+/// loops aren't stuck. Since the watchdog task itself runs on the same runtime, it also proves the
+/// executor is still making progress.
+pub struct Watchdog {
+    ping: watch::Sender<u64>,
+    heartbeats: Mutex<Vec<Weak<AtomicU64>>>,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog {
+            ping: watch::Sender::new(0),
+            heartbeats: Mutex::new(vec![]),
+        }
+    }
+
+    /// Returns a new heartbeat which must answer pings from now on. Dropping it unregisters it
+    /// again.
+    pub fn register(&self) -> Heartbeat {
+        let answered = Arc::new(AtomicU64::new(*self.ping.borrow()));
+        self.heartbeats.lock().unwrap().push(Arc::downgrade(&answered));
+        Heartbeat {
+            ping: self.ping.subscribe(),
+            answered,
+        }
+    }
+
+    /// Sends `WATCHDOG=1` every half `timeout` (as recommended by `sd_watchdog_enabled(3)`), as
+    /// long as everything is responsive.
+    pub async fn run(&self, timeout: Duration) {
+        let mut interval = time::interval(timeout / 2);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if self.beat().await {
+                if let Err(e) = systemd_socket::notify(false, "WATCHDOG=1") {
+                    eprintln!("Failed to notify systemd watchdog: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Checks whether all heartbeats have answered the last ping and whether a new task still gets
+    /// scheduled, then sends the next ping.
+    async fn beat(&self) -> bool {
+        let last_ping = *self.ping.borrow();
+        let stuck = {
+            let mut heartbeats = self.heartbeats.lock().unwrap();
+            heartbeats.retain(|heartbeat| heartbeat.strong_count() > 0);
+            heartbeats.iter()
+                .filter_map(Weak::upgrade)
+                .filter(|answered| answered.load(Ordering::Relaxed) != last_ping)
+                .count()
+        };
+        let executor_alive = tokio::spawn(async {}).await.is_ok();
+
+        self.ping.send_replace(last_ping + 1);
+
+        if stuck > 0 {
+            eprintln!("{} accept loop(s) did not respond in time, skipping watchdog notification.", stuck);
+        }
+        stuck == 0 && executor_alive
+    }
+}
+
+/// Held by a task which the [`Watchdog`] checks on.
+pub struct Heartbeat {
+    ping: watch::Receiver<u64>,
+    answered: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    /// Waits for the next ping and answers it. Should be raced against whatever the task is
+    /// waiting for, so it gets answered while the task is healthy.
+    pub async fn answer(&mut self) {
+        if self.ping.changed().await.is_err() {
+            // The watchdog is gone, so there is nobody to answer.
+            return std::future::pending().await;
+        }
+        let ping = *self.ping.borrow_and_update();
+        self.answered.store(ping, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchdog;
+
+    #[tokio::test]
+    async fn healthy_heartbeats() {
+        let watchdog = Watchdog::new();
+        let mut heartbeat = watchdog.register();
+        assert!(watchdog.beat().await);
+
+        heartbeat.answer().await;
+        assert!(watchdog.beat().await);
+        heartbeat.answer().await;
+        assert!(watchdog.beat().await);
+    }
+
+    #[tokio::test]
+    async fn stuck_heartbeat() {
+        let watchdog = Watchdog::new();
+        let mut heartbeat1 = watchdog.register();
+        let _heartbeat2 = watchdog.register();
+        assert!(watchdog.beat().await);
+
+        // The second heartbeat never answers.
+        heartbeat1.answer().await;
+        assert!(!watchdog.beat().await);
+    }
+
+    #[tokio::test]
+    async fn dropped_heartbeat() {
+        let watchdog = Watchdog::new();
+        let heartbeat = watchdog.register();
+        assert!(watchdog.beat().await);
+
+        drop(heartbeat);
+        assert!(watchdog.beat().await);
+        assert!(watchdog.heartbeats.lock().unwrap().is_empty());
+    }
+}